@@ -0,0 +1,8242 @@
+use actix_web::{
+    guard, web, App, HttpRequest, HttpResponse, HttpServer, Result as ActixResult,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::{from_fn, Compress, Condition, Logger, Next},
+};
+use actix_cors::Cors;
+use actix_ws::AggregatedMessage;
+use actix_web::body::{BodySize, EitherBody, MessageBody};
+use actix_web::cookie::Cookie;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use clap::{Arg, Command};
+use futures_util::StreamExt;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use rand::seq::IndexedRandom;
+use rand::RngExt;
+use regex::Regex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io::Write;
+use std::sync::{mpsc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+// Default reserved headers that should not be copied to the response
+const RESERVED_HEADERS: &[&str] = &[
+    "content-length",
+    "user-agent",
+    "host",
+    "connection",
+    "accept",
+    "accept-encoding",
+    "accept-language",
+    "cache-control",
+    "upgrade-insecure-requests",
+    "sec-fetch-dest",
+    "sec-fetch-mode",
+    "sec-fetch-site",
+    "sec-ch-ua",
+    "sec-ch-ua-mobile",
+    "sec-ch-ua-platform",
+    "authorization",
+    "cookie",
+    "referer",
+    "origin",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "transfer-encoding",
+    "te",
+    "trailer",
+    "proxy-authorization",
+    "proxy-authenticate",
+    "www-authenticate",
+];
+
+// Internal headers for controlling response
+const INTERNAL_HEADER_PREFIX: &str = "internal.";
+
+// Response header carrying the common name of a mutual TLS client certificate
+const CLIENT_CERT_SUBJECT_HEADER: &str = "x-client-cert-subject";
+// Response header carrying the `internal.checksum` of the request body
+const BODY_CHECKSUM_HEADER: &str = "x-body-checksum";
+// Response header carrying the connection's source address with `internal.reflect-peer`
+const PEER_ADDR_HEADER: &str = "x-peer-addr";
+// Response header with the --server-timing metrics
+const SERVER_TIMING_HEADER: &str = "server-timing";
+// Response header naming the [[rules]] and [[routes]] entries that decided the response, with --matched-rule-header
+const MATCHED_RULE_HEADER: &str = "x-matched-rule";
+// Value of the MATCHED_RULE_HEADER when no entry matched
+const MATCHED_RULE_ECHO: &str = "echo";
+const INTERNAL_STATUS_CODE_HEADER: &str = "internal.status-code";
+const INTERNAL_RESPONSE_BODY_HEADER: &str = "internal.response-body";
+const INTERNAL_RESPONSE_BODY_BASE64_HEADER: &str = "internal.response-body-base64";
+const INTERNAL_DELAY_MS_HEADER: &str = "internal.delay-ms";
+const INTERNAL_DELAY_JITTER_MS_HEADER: &str = "internal.delay-jitter-ms";
+const INTERNAL_CONTENT_TYPE_HEADER: &str = "internal.content-type";
+const INTERNAL_FORMAT_HEADER: &str = "internal.format";
+const INTERNAL_REPEAT_BODY_HEADER: &str = "internal.repeat-body";
+const INTERNAL_TRUNCATE_BODY_HEADER: &str = "internal.truncate-body";
+const INTERNAL_PAD_BYTES_HEADER: &str = "internal.pad-bytes";
+const INTERNAL_LOCATION_HEADER: &str = "internal.location";
+const INTERNAL_ECHO_HEADERS_HEADER: &str = "internal.echo-headers";
+const INTERNAL_CONNECTION_HEADER: &str = "internal.connection";
+const INTERNAL_ABORT_HEADER: &str = "internal.abort";
+const INTERNAL_SET_COOKIE_HEADER: &str = "internal.set-cookie";
+const INTERNAL_STREAM_HEADER: &str = "internal.stream";
+const INTERNAL_ADD_HEADER_HEADER: &str = "internal.add-header";
+const INTERNAL_RANDOM_STATUS_HEADER: &str = "internal.random-status";
+const INTERNAL_TEMPLATE_HEADER: &str = "internal.template";
+const INTERNAL_RETRY_AFTER_HEADER: &str = "internal.retry-after";
+const INTERNAL_CACHE_CONTROL_HEADER: &str = "internal.cache-control";
+const INTERNAL_CONTENT_LENGTH_HEADER: &str = "internal.content-length";
+const INTERNAL_TRAILER_HEADER: &str = "internal.trailer";
+const INTERNAL_RAW_HEADER: &str = "internal.raw";
+const INTERNAL_DRIP_MS_HEADER: &str = "internal.drip-ms";
+const INTERNAL_CHECKSUM_HEADER: &str = "internal.checksum";
+const INTERNAL_FORCE_ENCODING_HEADER: &str = "internal.force-encoding";
+const INTERNAL_REFLECT_PEER_HEADER: &str = "internal.reflect-peer";
+const INTERNAL_REFLECT_COOKIES_HEADER: &str = "internal.reflect-cookies";
+// Marks the --self-check request so it isn't counted, its value is only known to this process
+const INTERNAL_SELF_CHECK_HEADER: &str = "internal.self-check";
+const INTERNAL_REFLECT_QUERY_HEADER: &str = "internal.reflect-query";
+const INTERNAL_RENAME_HEADER_HEADER: &str = "internal.rename-header";
+const INTERNAL_DUPLICATE_HEADER_HEADER: &str = "internal.duplicate-header";
+const INTERNAL_HTTP_VERSION_HEADER: &str = "internal.http-version";
+
+// Separates several cookies given in a single `internal.set-cookie` value, commas can't be used since they appear in `Expires`
+const SET_COOKIE_DELIMITER: char = '|';
+
+// Query parameters for controlling response, for clients that can't easily set headers
+const QUERY_HEADER_PREFIX: &str = "h_";
+const QUERY_STATUS_PARAM: &str = "status";
+
+// Disables the automatic coloring of the verbose logging, see https://no-color.org
+const NO_COLOR_ENV_VAR: &str = "NO_COLOR";
+
+// ANSI SGR styles of the colored verbose logging
+const ANSI_BOLD: &str = "1";
+const ANSI_DIM: &str = "2";
+const ANSI_RED: &str = "1;31";
+const ANSI_GREEN: &str = "1;32";
+const ANSI_YELLOW: &str = "1;33";
+const ANSI_CYAN: &str = "1;36";
+
+// Settings file loaded when --config isn't given
+const DEFAULT_SETTINGS_PATH: &str = "Settings.toml";
+
+// Environment variables overriding the settings file, but not the command line
+const HOST_ENV_VAR: &str = "GRECHO_HOST";
+const PORT_ENV_VAR: &str = "GRECHO_PORT";
+
+// Default path of the liveness endpoint
+const DEFAULT_HEALTH_PATH: &str = "/health";
+
+// Path of the request statistics endpoint
+const STATS_PATH: &str = "/stats";
+
+// Path of the Prometheus metrics endpoint
+const METRICS_PATH: &str = "/metrics";
+
+// Path of the effective configuration endpoint
+const CONFIG_PATH: &str = "/__config";
+
+// Path of the endpoint stopping the server, only enabled with --shutdown-token
+const SHUTDOWN_PATH: &str = "/__shutdown";
+
+// Upper bounds, in seconds, of the handler latency histogram buckets
+const LATENCY_BUCKETS_SECS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+// Handler latencies kept for the shutdown percentiles, later ones replace random samples
+const LATENCY_RESERVOIR_SIZE: usize = 10_000;
+
+// Path of the WebSocket echo endpoint
+const WEBSOCKET_PATH: &str = "/ws";
+
+// Prefix of the paths answering with the status code given as their last segment, e.g. `/status/503`
+const STATUS_PATH_PREFIX: &str = "/status/";
+
+// Path browsers request on their own, answered with 204 No Content unless --echo-favicon is given
+const FAVICON_PATH: &str = "/favicon.ico";
+
+// Defaults of the `bench` subcommand
+const DEFAULT_BENCH_REQUESTS: u64 = 10_000;
+const DEFAULT_BENCH_CONCURRENCY: u64 = 50;
+const DEFAULT_BENCH_BODY_SIZE: u64 = 1024;
+
+// Default time given to in-flight requests to complete on shutdown
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+// How long idle connections are kept open, the actix default
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 5;
+
+// Upper bound for the response delay, to keep a single request from hanging forever
+const MAX_DELAY_MS: u64 = 300_000;
+
+// Upper bounds for the repeated body, to keep a single request from exhausting memory
+const MAX_REPEAT_BODY_COUNT: usize = 10_000;
+const MAX_REPEATED_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+// Default limit for `internal.pad-bytes`, the body is generated in memory
+const DEFAULT_MAX_PAD_BYTES: usize = 64 * 1024 * 1024;
+
+// Repeated to fill `internal.pad-bytes` bodies, byte i is PAD_PATTERN[i % PAD_PATTERN.len()]
+const PAD_PATTERN: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+// Most times `internal.duplicate-header` can send a header, proxies only need a few to misbehave
+const MAX_HEADER_DUPLICATES: usize = 100;
+
+// Path of the --self-check request under the base path, so it reaches the echo handler
+const SELF_CHECK_PATH: &str = "/__self-check";
+
+// How long --self-check waits to connect and for the response
+const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Most chunks a dripped body is split into, larger bodies get chunks of several bytes
+const MAX_DRIP_CHUNKS: usize = 1_000;
+
+// Most connections a worker serves at once, the actix default
+const DEFAULT_MAX_CONNECTIONS: usize = 25_000;
+
+// Pending connections limit of every listening socket, the actix default
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+// Quiet time after a change to the TLS files before they are reloaded
+const TLS_RELOAD_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+// Default limit for request bodies, which are buffered in memory before being echoed
+const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+// Apache Common Log Format, `%{clf_time}xi` is filled in by access_logger
+const CLF_LOG_FORMAT: &str = "%a - - [%{clf_time}xi] \"%r\" %s %b";
+
+#[derive(Debug, Deserialize)]
+struct Settings {
+    #[serde(default = "default_host")]
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    https_port: Option<u16>,
+    health_path: Option<String>,
+    base_path: Option<String>,
+    reserved_headers: Option<Vec<String>>,
+    extra_reserved_headers: Option<Vec<String>>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_client_ca: Option<String>,
+    workers: Option<usize>,
+    default_status: Option<u16>,
+    default_content_type: Option<String>,
+    cors: Option<CorsSettings>,
+    #[serde(default)]
+    routes: Vec<RouteSettings>,
+    #[serde(default)]
+    rules: Vec<RuleSettings>,
+}
+
+/// Canned body and/or status for the paths matching a glob, from the [[routes]] settings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteSettings {
+    /// Shown in the `X-Matched-Rule` header instead of the path
+    pub name: Option<String>,
+    pub path: String,
+    pub body: Option<String>,
+    pub status: Option<u16>,
+}
+
+/// Response for the paths matching a regex, from the [[rules]] settings: either `echo = true`, or
+/// a canned `status` and/or `body`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RuleSettings {
+    /// Shown in the `X-Matched-Rule` header instead of the regex
+    pub name: Option<String>,
+    pub path_regex: String,
+    #[serde(default)]
+    pub echo: bool,
+    pub status: Option<u16>,
+    pub body: Option<String>,
+}
+
+/// Allowed CORS origins, methods and headers, any of them when omitted or set to ["*"]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CorsSettings {
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+}
+
+impl CorsSettings {
+    // Only --cors turns CORS on, the [cors] section just configures it (anything allowed without one)
+    fn resolve(enabled: bool, section: Option<&CorsSettings>) -> Option<CorsSettings> {
+        enabled.then(|| section.cloned().unwrap_or_default())
+    }
+
+    fn build(&self) -> Cors {
+        fn is_any(values: &Option<Vec<String>>) -> bool {
+            values.as_ref().is_none_or(|values| values.iter().any(|v| v == "*"))
+        }
+
+        // Expose every header so browser scripts can read the echoed ones
+        let mut cors = Cors::default().expose_any_header();
+
+        cors = match &self.allowed_origins {
+            origins if is_any(origins) => cors.allow_any_origin(),
+            Some(origins) => origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin)),
+            None => cors,
+        };
+        cors = match &self.allowed_methods {
+            methods if is_any(methods) => cors.allow_any_method(),
+            Some(methods) => cors.allowed_methods(methods.iter().map(|m| m.as_str())),
+            None => cors,
+        };
+        cors = match &self.allowed_headers {
+            headers if is_any(headers) => cors.allow_any_header(),
+            Some(headers) => cors.allowed_headers(headers.iter().map(|h| h.as_str())),
+            None => cors,
+        };
+
+        cors
+    }
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    8001
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            host: default_host(),
+            port: default_port(),
+            https_port: None,
+            health_path: None,
+            base_path: None,
+            reserved_headers: None,
+            extra_reserved_headers: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            workers: None,
+            default_status: None,
+            default_content_type: None,
+            cors: None,
+            routes: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+// Lowercased names of the request headers that are never echoed back
+#[derive(Debug)]
+struct ReservedHeaders(HashSet<String>);
+
+impl ReservedHeaders {
+    fn new(names: &[String]) -> Self {
+        ReservedHeaders(names.iter().map(|h| h.to_lowercase()).collect())
+    }
+
+    // The --reserved-headers-file list, or else `reserved_headers`, replaces the built-in list and
+    // `extra_reserved_headers` is appended to it
+    fn from_settings(settings: &Settings, file_headers: Option<&[String]>) -> Self {
+        let mut reserved: HashSet<String> = match file_headers.or(settings.reserved_headers.as_deref()) {
+            Some(headers) => headers.iter().map(|h| h.to_lowercase()).collect(),
+            None => RESERVED_HEADERS.iter().map(|h| h.to_string()).collect(),
+        };
+        if let Some(extra) = &settings.extra_reserved_headers {
+            reserved.extend(extra.iter().map(|h| h.to_lowercase()));
+        }
+        ReservedHeaders(reserved)
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.0.contains(&name.to_lowercase())
+    }
+}
+
+// One header name per line, blank lines and `#` comments are skipped
+fn load_reserved_headers_file(path: &str) -> Result<Vec<String>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read reserved headers file '{}': {}.", path, e))?;
+    let mut headers = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let name = line.trim();
+        if name.is_empty() || name.starts_with('#') {
+            continue;
+        }
+        if HeaderName::from_str(name).is_err() {
+            return Err(format!("Invalid header name '{}' on line {} of '{}'.", name, number + 1, path));
+        }
+        headers.push(name.to_string());
+    }
+    if headers.is_empty() {
+        return Err(format!("Reserved headers file '{}' does not list any header.", path));
+    }
+    Ok(headers)
+}
+
+// Lowercased, so `Settings.YAML` or `replay.JSON` are read in the right format too
+fn file_extension(path: &str) -> Option<String> {
+    std::path::Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_lowercase)
+}
+
+impl Settings {
+    // The format follows the extension: JSON for .json, YAML for .yaml or .yml, TOML otherwise
+    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let settings_content = std::fs::read_to_string(path)?;
+        let settings: Settings = match file_extension(path).as_deref() {
+            Some("json") => serde_json::from_str(&settings_content).map_err(|e| format!("Invalid JSON: {}", e))?,
+            Some("yaml" | "yml") => serde_yaml_ng::from_str(&settings_content).map_err(|e| format!("Invalid YAML: {}", e))?,
+            _ => toml::from_str(&settings_content).map_err(|e| format!("Invalid TOML: {}", e))?,
+        };
+        Ok(settings)
+    }
+}
+
+/// How the echoed response body is built
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// The request body, as is
+    #[default]
+    Echo,
+    /// A JSON description of the whole request
+    Json,
+    /// The names, file names, content types and sizes of the parts of a multipart body, as JSON
+    #[serde(rename = "multipart-summary")]
+    MultipartSummary,
+    /// An offset/hex/ASCII dump of the body as text, like `hexdump -C` prints it
+    Hexdump,
+}
+
+impl FromStr for ResponseFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "echo" => Ok(ResponseFormat::Echo),
+            "json" => Ok(ResponseFormat::Json),
+            "multipart-summary" => Ok(ResponseFormat::MultipartSummary),
+            "hexdump" => Ok(ResponseFormat::Hexdump),
+            _ => Err(format!("Invalid format '{}'. Must be 'echo', 'json', 'multipart-summary' or 'hexdump'.", s)),
+        }
+    }
+}
+
+// 16 bytes per line as in `hexdump -C`: the offset, the bytes in hex in two groups of 8 and the
+// printable ones in ASCII, then the length on a line of its own. Repeated lines aren't squeezed.
+fn hexdump(body: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in body.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x}  ", line * 16);
+        for slot in 0..16 {
+            match chunk.get(slot) {
+                Some(byte) => {
+                    let _ = write!(dump, "{:02x} ", byte);
+                }
+                None => dump.push_str("   "),
+            }
+            if slot == 7 {
+                dump.push(' ');
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        let _ = writeln!(dump, " |{}|", ascii);
+    }
+    if !body.is_empty() {
+        let _ = writeln!(dump, "{:08x}", body.len());
+    }
+    dump
+}
+
+#[derive(Debug, Serialize)]
+struct JsonEcho<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    headers: BTreeMap<&'a str, String>,
+    body: String,
+    // "utf-8" for text bodies, "base64" for binary ones
+    body_encoding: &'static str,
+}
+
+// Checksum of the request body returned with `internal.checksum`, to verify uploads without
+// echoing them
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+    Crc32,
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "md5" => Ok(ChecksumAlgorithm::Md5),
+            "crc32" => Ok(ChecksumAlgorithm::Crc32),
+            _ => Err(format!(
+                "Invalid '{}' value '{}'. Must be 'sha256', 'md5' or 'crc32'.",
+                INTERNAL_CHECKSUM_HEADER, s
+            )),
+        }
+    }
+}
+
+impl ChecksumAlgorithm {
+    // Lowercase hex, CRC-32 as its 8 digit big-endian value like `crc32` tools print it
+    fn hex_digest(&self, body: &[u8]) -> String {
+        use sha2::Digest;
+
+        let digest = match self {
+            ChecksumAlgorithm::Sha256 => sha2::Sha256::digest(body).to_vec(),
+            ChecksumAlgorithm::Md5 => md5::Md5::digest(body).to_vec(),
+            ChecksumAlgorithm::Crc32 => crc32fast::hash(body).to_be_bytes().to_vec(),
+        };
+        digest.iter().fold(String::with_capacity(digest.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+    }
+}
+
+// Content-Encoding applied with `internal.force-encoding` whatever the request's Accept-Encoding,
+// which is deliberately non-compliant to test clients that mishandle unexpected encodings
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ForcedEncoding {
+    Gzip,
+}
+
+impl FromStr for ForcedEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "gzip" => Ok(ForcedEncoding::Gzip),
+            _ => Err(format!("Invalid '{}' value '{}'. Must be 'gzip'.", INTERNAL_FORCE_ENCODING_HEADER, s)),
+        }
+    }
+}
+
+impl ForcedEncoding {
+    // The Content-Encoding token
+    fn name(&self) -> &'static str {
+        match self {
+            ForcedEncoding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> std::io::Result<web::Bytes> {
+        match self {
+            ForcedEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                Ok(web::Bytes::from(encoder.finish()?))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MultipartSummary {
+    parts: Vec<MultipartPart>,
+}
+
+#[derive(Debug, Serialize)]
+struct MultipartPart {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size: usize,
+}
+
+impl MultipartSummary {
+    // The body is already buffered, so it's handed to the multipart parser as a single chunk
+    async fn from_request(req: &HttpRequest, body: web::Bytes) -> Result<Self, String> {
+        let invalid = |e: actix_multipart::MultipartError| {
+            format!("'{}: multipart-summary' needs a valid multipart body: {}", INTERNAL_FORMAT_HEADER, e)
+        };
+        let body = futures_util::stream::once(async move { Ok::<_, actix_web::error::PayloadError>(body) });
+        let mut multipart = actix_multipart::Multipart::new(req.headers(), body);
+
+        let mut parts = Vec::new();
+        while let Some(field) = multipart.next().await {
+            let mut field = field.map_err(invalid)?;
+            let mut size = 0;
+            while let Some(chunk) = field.next().await {
+                size += chunk.map_err(invalid)?.len();
+            }
+            parts.push(MultipartPart {
+                name: field.name().map(str::to_string),
+                filename: field.content_disposition().and_then(|cd| cd.get_filename()).map(str::to_string),
+                content_type: field.content_type().map(|mime| mime.to_string()),
+                size,
+            });
+        }
+
+        Ok(MultipartSummary { parts })
+    }
+}
+
+impl<'a> JsonEcho<'a> {
+    fn from_request(req: &'a HttpRequest, body: &[u8]) -> Self {
+        let (body, body_encoding) = match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), "utf-8"),
+            Err(_) => (base64::engine::general_purpose::STANDARD.encode(body), "base64"),
+        };
+
+        JsonEcho {
+            method: req.method().as_str(),
+            path: req.path(),
+            query: req.query_string(),
+            headers: headers_map(req.headers()),
+            body,
+            body_encoding,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RecordedRequest<'a> {
+    // Seconds since the Unix epoch
+    timestamp: f64,
+    #[serde(flatten)]
+    request: JsonEcho<'a>,
+}
+
+// Appends a JSON line per echoed request to the --record file. Lines are written by a dedicated
+// thread, so handlers only pay for serializing and queueing them.
+#[derive(Debug, Default)]
+struct Recorder(Option<mpsc::Sender<String>>);
+
+impl Recorder {
+    fn open(path: &str) -> Result<Self, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Could not open record file '{}': {}.", path, e))?;
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        let path = path.to_string();
+        std::thread::spawn(move || {
+            let mut writer = std::io::BufWriter::new(file);
+            while let Ok(line) = receiver.recv() {
+                let mut written = writeln!(writer, "{}", line);
+                // Only flush once the queue is drained, so bursts are written in one go
+                while let (Ok(()), Ok(line)) = (&written, receiver.try_recv()) {
+                    written = writeln!(writer, "{}", line);
+                }
+                if let Err(e) = written.and_then(|_| writer.flush()) {
+                    eprintln!("Error: Could not write to record file '{}': {}", path, e);
+                }
+            }
+        });
+
+        Ok(Recorder(Some(sender)))
+    }
+
+    fn record(&self, req: &HttpRequest, body: &[u8]) {
+        let Some(sender) = &self.0 else {
+            return;
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or_default();
+        let entry = RecordedRequest {
+            timestamp,
+            request: JsonEcho::from_request(req, body),
+        };
+        match serde_json::to_string(&entry) {
+            Ok(line) => {
+                let _ = sender.send(line);
+            }
+            Err(e) => eprintln!("Error: Could not serialize recorded request: {}", e),
+        }
+    }
+}
+
+// Counters of the requests handled by the echo handler
+#[derive(Debug, Default)]
+struct Stats {
+    total: AtomicU64,
+    by_method: Mutex<BTreeMap<String, u64>>,
+    by_path: Mutex<BTreeMap<String, u64>>,
+    untracked_paths: AtomicU64,
+}
+
+// Distinct paths counted in /stats, requests to further paths are only counted as untracked so a
+// fuzzing client can't grow the map without bounds
+const MAX_TRACKED_PATHS: usize = 1_000;
+
+#[derive(Debug, Serialize)]
+struct StatsSnapshot {
+    total: u64,
+    methods: BTreeMap<String, u64>,
+    paths: BTreeMap<String, u64>,
+    untracked_paths: u64,
+}
+
+impl Stats {
+    fn record(&self, req: &HttpRequest) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut by_method) = self.by_method.lock() {
+            *by_method.entry(req.method().to_string()).or_insert(0) += 1;
+        }
+        if let Ok(mut by_path) = self.by_path.lock() {
+            if let Some(count) = by_path.get_mut(req.path()) {
+                *count += 1;
+            } else if by_path.len() < MAX_TRACKED_PATHS {
+                by_path.insert(req.path().to_string(), 1);
+            } else {
+                self.untracked_paths.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            total: self.total.load(Ordering::Relaxed),
+            methods: self.by_method.lock().map(|m| m.clone()).unwrap_or_default(),
+            paths: self.by_path.lock().map(|p| p.clone()).unwrap_or_default(),
+            untracked_paths: self.untracked_paths.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.total.store(0, Ordering::Relaxed);
+        self.untracked_paths.store(0, Ordering::Relaxed);
+        if let Ok(mut by_method) = self.by_method.lock() {
+            by_method.clear();
+        }
+        if let Ok(mut by_path) = self.by_path.lock() {
+            by_path.clear();
+        }
+    }
+}
+
+// Prometheus metrics of the requests handled by the echo handler
+#[derive(Debug, Default)]
+struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    latency: Mutex<LatencyHistogram>,
+    samples: Mutex<LatencyReservoir>,
+}
+
+// Uniform sample of at most LATENCY_RESERVOIR_SIZE latencies (reservoir sampling), so memory stays
+// bounded however many requests are handled. The extremes are tracked exactly.
+#[derive(Debug, Default)]
+struct LatencyReservoir {
+    samples: Vec<Duration>,
+    seen: u64,
+    min: Duration,
+    max: Duration,
+}
+
+impl LatencyReservoir {
+    fn observe(&mut self, elapsed: Duration) {
+        self.min = if self.seen == 0 { elapsed } else { self.min.min(elapsed) };
+        self.max = self.max.max(elapsed);
+        self.seen += 1;
+        if self.samples.len() < LATENCY_RESERVOIR_SIZE {
+            self.samples.push(elapsed);
+        } else {
+            let slot = rand::rng().random_range(0..self.seen);
+            if let Some(sample) = self.samples.get_mut(slot as usize) {
+                *sample = elapsed;
+            }
+        }
+    }
+
+    fn summary(&self) -> Option<LatencySummary> {
+        if self.seen == 0 {
+            return None;
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        // Nearest-rank percentile
+        let percentile = |p: f64| sorted[((p / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1];
+        Some(LatencySummary {
+            count: self.seen,
+            min: self.min,
+            p50: percentile(50.0),
+            p90: percentile(90.0),
+            p99: percentile(99.0),
+            max: self.max,
+        })
+    }
+}
+
+/// Echo handler latencies, percentiles are estimated from a bounded sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl std::fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{} request(s): min {:.3}ms, p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            self.count,
+            ms(self.min),
+            ms(self.p50),
+            ms(self.p90),
+            ms(self.p99),
+            ms(self.max)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct LatencyHistogram {
+    // Not cumulative, one count per entry of LATENCY_BUCKETS_SECS plus one for +Inf
+    buckets: Vec<u64>,
+    sum_secs: f64,
+    count: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: vec![0; LATENCY_BUCKETS_SECS.len() + 1],
+            sum_secs: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Metrics {
+    fn observe(&self, method: &str, status: u16, elapsed: Duration) {
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests.entry((method.to_string(), status)).or_insert(0) += 1;
+        }
+
+        if let Ok(mut latency) = self.latency.lock() {
+            let secs = elapsed.as_secs_f64();
+            let bucket = LATENCY_BUCKETS_SECS
+                .iter()
+                .position(|&upper_bound| secs <= upper_bound)
+                .unwrap_or(LATENCY_BUCKETS_SECS.len());
+            latency.buckets[bucket] += 1;
+            latency.sum_secs += secs;
+            latency.count += 1;
+        }
+
+        if let Ok(mut samples) = self.samples.lock() {
+            samples.observe(elapsed);
+        }
+    }
+
+    fn latency_summary(&self) -> Option<LatencySummary> {
+        self.samples.lock().ok()?.summary()
+    }
+
+    // Renders the metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("# HELP grecho_requests_total Echoed requests by method and status.\n");
+        output.push_str("# TYPE grecho_requests_total counter\n");
+        if let Ok(requests) = self.requests.lock() {
+            for ((method, status), count) in requests.iter() {
+                output.push_str(&format!(
+                    "grecho_requests_total{{method=\"{}\",status=\"{}\"}} {}\n",
+                    method, status, count
+                ));
+            }
+        }
+
+        output.push_str("# HELP grecho_request_duration_seconds Echo handler latency.\n");
+        output.push_str("# TYPE grecho_request_duration_seconds histogram\n");
+        if let Ok(latency) = self.latency.lock() {
+            let mut cumulative = 0;
+            for (i, upper_bound) in LATENCY_BUCKETS_SECS.iter().enumerate() {
+                cumulative += latency.buckets[i];
+                output.push_str(&format!(
+                    "grecho_request_duration_seconds_bucket{{le=\"{}\"}} {}\n",
+                    upper_bound, cumulative
+                ));
+            }
+            output.push_str(&format!("grecho_request_duration_seconds_bucket{{le=\"+Inf\"}} {}\n", latency.count));
+            output.push_str(&format!("grecho_request_duration_seconds_sum {}\n", latency.sum_secs));
+            output.push_str(&format!("grecho_request_duration_seconds_count {}\n", latency.count));
+        }
+
+        output
+    }
+}
+
+// RFC 9457 problem details
+#[derive(Debug, Serialize)]
+struct ProblemDetails {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+
+impl ProblemDetails {
+    fn internal_server_error(detail: &str, errors: Vec<String>) -> HttpResponse {
+        let problem = ProblemDetails {
+            problem_type: "about:blank",
+            title: "Internal Server Error",
+            status: 500,
+            detail: detail.to_string(),
+            errors,
+        };
+        HttpResponse::InternalServerError()
+            .content_type("application/problem+json")
+            .json(problem)
+    }
+
+    fn bad_request(detail: &str, errors: Vec<String>) -> HttpResponse {
+        let problem = ProblemDetails {
+            problem_type: "about:blank",
+            title: "Bad Request",
+            status: 400,
+            detail: detail.to_string(),
+            errors,
+        };
+        HttpResponse::BadRequest()
+            .content_type("application/problem+json")
+            .json(problem)
+    }
+}
+
+// Rejected internal control values are plain text by default and problem+json in strict mode
+fn bad_request(strict: bool, detail: String) -> HttpResponse {
+    if strict {
+        ProblemDetails::bad_request(&detail, Vec::new())
+    } else {
+        HttpResponse::BadRequest().body(detail)
+    }
+}
+
+// Describes every internal header (and the status query parameter) whose value can't be parsed,
+// values that parse but are out of range are reported by the handler itself
+fn malformed_internal_controls(req: &HttpRequest) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (name, value) in req.headers().iter() {
+        if name.as_str().starts_with(INTERNAL_HEADER_PREFIX) && value.to_str().is_err() {
+            errors.push(format!("'{}' is not valid text.", name));
+        }
+    }
+
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Some(status) = header(INTERNAL_STATUS_CODE_HEADER) {
+        if let Err(e) = validate_status(status) {
+            errors.push(format!("'{}': {}", INTERNAL_STATUS_CODE_HEADER, e));
+        }
+    }
+    if let Some(delay) = header(INTERNAL_DELAY_MS_HEADER) {
+        if delay.parse::<u64>().is_err() {
+            errors.push(format!("'{}': '{}' is not a number of milliseconds.", INTERNAL_DELAY_MS_HEADER, delay));
+        }
+    }
+    if let Some(jitter) = header(INTERNAL_DELAY_JITTER_MS_HEADER) {
+        if jitter.parse::<u64>().is_err() {
+            errors.push(format!("'{}': '{}' is not a number of milliseconds.", INTERNAL_DELAY_JITTER_MS_HEADER, jitter));
+        }
+    }
+    if let Some(content_type) = header(INTERNAL_CONTENT_TYPE_HEADER) {
+        if parse_content_type(content_type).is_none() {
+            errors.push(format!("'{}': '{}' is not a valid MIME type.", INTERNAL_CONTENT_TYPE_HEADER, content_type));
+        }
+    }
+    if let Some(format) = header(INTERNAL_FORMAT_HEADER) {
+        if let Err(e) = format.parse::<ResponseFormat>() {
+            errors.push(format!("'{}': {}", INTERNAL_FORMAT_HEADER, e));
+        }
+    }
+    if let Some(drip) = header(INTERNAL_DRIP_MS_HEADER) {
+        if drip.parse::<u64>().is_err() {
+            errors.push(format!("'{}': '{}' is not a number of milliseconds.", INTERNAL_DRIP_MS_HEADER, drip));
+        }
+    }
+    if let Some(algorithm) = header(INTERNAL_CHECKSUM_HEADER) {
+        if let Err(e) = algorithm.parse::<ChecksumAlgorithm>() {
+            errors.push(e);
+        }
+    }
+    if let Some(encoded) = header(INTERNAL_RESPONSE_BODY_BASE64_HEADER) {
+        if let Err(e) = decode_response_body_base64(encoded) {
+            errors.push(e);
+        }
+    }
+    if let Some(encoding) = header(INTERNAL_FORCE_ENCODING_HEADER) {
+        if let Err(e) = encoding.parse::<ForcedEncoding>() {
+            errors.push(e);
+        }
+    }
+    if let Some(version) = header(INTERNAL_HTTP_VERSION_HEADER) {
+        if let Err(e) = parse_http_version(version) {
+            errors.push(e);
+        }
+    }
+    if let Some(length) = header(INTERNAL_TRUNCATE_BODY_HEADER) {
+        if length.trim().parse::<usize>().is_err() {
+            errors.push(format!("'{}': '{}' is not a number of bytes.", INTERNAL_TRUNCATE_BODY_HEADER, length));
+        }
+    }
+    if let Some(length) = header(INTERNAL_PAD_BYTES_HEADER) {
+        if length.trim().parse::<usize>().is_err() {
+            errors.push(format!("'{}': '{}' is not a number of bytes.", INTERNAL_PAD_BYTES_HEADER, length));
+        }
+    }
+    if let Some(count) = header(INTERNAL_REPEAT_BODY_HEADER) {
+        if count.trim().parse::<usize>().is_err() {
+            errors.push(format!("'{}': '{}' is not a number.", INTERNAL_REPEAT_BODY_HEADER, count));
+        }
+    }
+
+    for entry in req.headers().get_all(INTERNAL_RENAME_HEADER_HEADER).filter_map(|v| v.to_str().ok()) {
+        if let Err(e) = parse_header_rename(entry) {
+            errors.push(e);
+        }
+    }
+    for entry in req.headers().get_all(INTERNAL_DUPLICATE_HEADER_HEADER).filter_map(|v| v.to_str().ok()) {
+        if let Err(e) = parse_header_duplicate(entry) {
+            errors.push(e);
+        }
+    }
+
+    let query_params = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    if let Some((_, status)) = query_params.iter().find(|(name, _)| name == QUERY_STATUS_PARAM) {
+        if let Err(e) = validate_status(status) {
+            errors.push(format!("'{}' query parameter: {}", QUERY_STATUS_PARAM, e));
+        }
+    }
+
+    errors
+}
+
+// Records the method, status and latency of every echoed request
+async fn record_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    let method = req.method().to_string();
+    let started_at = Instant::now();
+
+    let self_check = is_self_check(req.headers());
+
+    let response = next.call(req).await?;
+    if let Some(metrics) = metrics.filter(|_| !self_check) {
+        metrics.observe(&method, response.status().as_u16(), started_at.elapsed());
+    }
+
+    Ok(response)
+}
+
+// Credentials from --auth, requests to the echo endpoint must present them with HTTP Basic auth
+#[derive(Debug, Default)]
+struct BasicAuth(Option<String>);
+
+impl BasicAuth {
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(credentials) = &self.0 else {
+            return true;
+        };
+
+        headers
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok())
+            .is_some_and(|decoded| decoded == credentials.as_bytes())
+    }
+}
+
+// Rejects echo requests without valid Basic credentials when --auth is set
+async fn require_basic_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let authorized = req
+        .app_data::<web::Data<BasicAuth>>()
+        .is_none_or(|auth| auth.is_authorized(req.headers()));
+    if authorized {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let response = HttpResponse::Unauthorized()
+        .insert_header((actix_web::http::header::WWW_AUTHENTICATE, "Basic realm=\"grecho\""))
+        .body("Unauthorized");
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+// Address range in CIDR notation like `10.0.0.0/8` or `fd00::/8`, a bare address matches only itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IpNet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid IP address or CIDR range '{}'. Must look like 10.0.0.0/8 or ::1.", s);
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s.trim(), None),
+        };
+        let network = IpAddr::from_str(address).map_err(|_| invalid())?.to_canonical();
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_prefix_len);
+        if prefix_len > max_prefix_len {
+            return Err(invalid());
+        }
+
+        Ok(IpNet { network, prefix_len })
+    }
+}
+
+impl IpNet {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (u128::from(u32::from(network)), u128::from(u32::from(ip)), 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        self.prefix_len == 0 || (network ^ ip) >> (bits - u32::from(self.prefix_len)) == 0
+    }
+}
+
+// Source addresses allowed with --allow-ip, every client is allowed when empty
+#[derive(Debug, Default)]
+struct IpAllowlist {
+    networks: Vec<IpNet>,
+    // Take the client address from the last X-Forwarded-For entry, the one the proxy appended
+    trust_forwarded_for: bool,
+}
+
+impl IpAllowlist {
+    fn new(networks: &[String], trust_forwarded_for: bool) -> Result<Self, String> {
+        let networks = networks.iter().map(|network| network.parse()).collect::<Result<_, _>>()?;
+        Ok(IpAllowlist { networks, trust_forwarded_for })
+    }
+
+    fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let forwarded_for = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .last()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| IpAddr::from_str(ip.trim()).ok())
+            .filter(|_| self.trust_forwarded_for);
+        forwarded_for.or_else(|| req.peer_addr().map(|addr| addr.ip()))
+    }
+
+    // Requests without a known address, like the ones over a Unix domain socket, are rejected
+    fn is_allowed(&self, req: &ServiceRequest) -> bool {
+        self.networks.is_empty()
+            || self.client_ip(req).is_some_and(|ip| self.networks.iter().any(|network| network.contains(ip)))
+    }
+}
+
+async fn require_allowed_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let allowed = req
+        .app_data::<web::Data<IpAllowlist>>()
+        .is_none_or(|allowlist| allowlist.is_allowed(&req));
+    if allowed {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let response = HttpResponse::Forbidden().body("Forbidden: client address is not allowed");
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+// State of --once: the first echoed request is served, the ones after it are rejected while the
+// server shuts down
+#[derive(Debug, Default)]
+struct ServeOnce {
+    enabled: bool,
+    claimed: AtomicBool,
+    served: Notify,
+}
+
+impl ServeOnce {
+    fn new(enabled: bool) -> Self {
+        ServeOnce {
+            enabled,
+            ..ServeOnce::default()
+        }
+    }
+}
+
+async fn serve_once(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let Some(once) = req.app_data::<web::Data<ServeOnce>>().filter(|once| once.enabled).cloned() else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    if once.claimed.swap(true, Ordering::SeqCst) {
+        let response = HttpResponse::ServiceUnavailable()
+            .force_close()
+            .body("Server only handles a single request (--once) and is shutting down");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let response = next.call(req).await;
+    // The response is still sent, the shutdown is graceful
+    once.served.notify_one();
+    response.map(ServiceResponse::map_into_left_body)
+}
+
+// --shutdown-token, POST /__shutdown with it as a Bearer token wakes up whoever waits on `requested`
+#[derive(Debug, Default)]
+struct ShutdownTrigger {
+    token: Option<String>,
+    requested: Notify,
+}
+
+async fn shutdown_handler(req: HttpRequest, trigger: web::Data<ShutdownTrigger>) -> HttpResponse {
+    let Some(token) = &trigger.token else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let authorized = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|supplied| constant_time_eq(supplied.trim(), token));
+    if !authorized {
+        return HttpResponse::Forbidden().body("Invalid shutdown token");
+    }
+
+    // The response is still sent, the shutdown is graceful
+    trigger.requested.notify_one();
+    HttpResponse::Accepted().body("Shutting down")
+}
+
+// Compares the SHA-256 digests of both values, so the time taken reveals neither the length of the
+// secret nor how many leading bytes matched
+fn constant_time_eq(supplied: &str, secret: &str) -> bool {
+    use sha2::Digest;
+
+    let supplied = sha2::Sha256::digest(supplied.as_bytes());
+    let secret = sha2::Sha256::digest(secret.as_bytes());
+    supplied.iter().zip(secret.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+// Writes the response header names in Title-Case (`X-Custom-Header`) with --preserve-header-case.
+// actix lowercases the request header names while parsing and drops the bytes the client sent, so
+// Title-Case is the closest to the original casing of most clients. HTTP/2 names are always lowercase.
+async fn title_case_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let enabled = req.app_data::<web::Data<Config>>().is_some_and(|config| config.preserve_header_case);
+
+    let mut response = next.call(req).await?;
+    if enabled {
+        response.response_mut().head_mut().set_camel_case_headers(true);
+    }
+
+    Ok(response)
+}
+
+/// Everything a server started by [`start_server`] runs with. The command line fills it from the
+/// arguments, environment and settings file, and it is served at /__config with secrets masked.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerConfig {
+    /// Settings file the configuration was read from, only reported at /__config
+    pub settings_path: Option<String>,
+    /// TCP addresses to listen on, port 0 picks a free port
+    pub bind_addresses: Vec<SocketAddr>,
+    /// TCP addresses serving HTTPS while the `bind_addresses` stay plain HTTP. When empty, the
+    /// `bind_addresses` serve HTTPS if `tls_cert` and `tls_key` are set.
+    pub https_bind_addresses: Vec<SocketAddr>,
+    /// Unix domain socket to listen on, in addition to the TCP addresses
+    pub uds: Option<String>,
+    /// Let IPv6 addresses accept IPv4 connections too
+    pub dual_stack: bool,
+    /// PEM certificate chain and private key, serving HTTPS when both are set
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// PEM CA certificates that client certificates must be signed by, requiring mutual TLS when set
+    pub tls_client_ca: Option<String>,
+    /// Accept HTTP/2 prior knowledge connections alongside HTTP/1.1 when serving plain HTTP
+    pub h2c: bool,
+    /// Refuse `Expect: 100-continue` with 417 Expectation Failed instead of sending the interim
+    /// 100 Continue, not supported over a Unix domain socket
+    pub no_continue: bool,
+    pub workers: usize,
+    /// How long in-flight requests may take to complete on a graceful stop
+    pub shutdown_timeout_secs: u64,
+    /// How long idle connections are kept open for further requests, 0 closes them after every response
+    pub keep_alive_secs: u64,
+    /// Most connections each worker serves at once, actix's default of 25k when unset. Further
+    /// connections wait in the listen backlog until one closes.
+    pub max_connections: Option<usize>,
+    /// Connections the kernel queues until they are accepted, actix's default of 1024 when unset
+    pub backlog: Option<u32>,
+    pub health_path: String,
+    /// Prefix like `/echo` that every endpoint is served under, empty to serve at the root
+    pub base_path: String,
+    /// Request headers that are never echoed, case-insensitive
+    pub reserved_headers: Vec<String>,
+    /// Write response header names in Title-Case on HTTP/1.x instead of lowercase
+    pub preserve_header_case: bool,
+    /// Add a `Server-Timing` header with the processing time and the injected delay to echoed responses
+    pub server_timing: bool,
+    pub default_format: ResponseFormat,
+    /// Status of responses that don't override it
+    pub default_status: u16,
+    /// `Content-Type` of echoed responses that end up without one
+    pub default_content_type: Option<String>,
+    /// Largest accepted request body, in bytes
+    pub max_body_size: usize,
+    /// Largest accepted `internal.delay-ms`
+    pub max_delay_ms: u64,
+    /// Largest accepted `internal.repeat-body`, and largest size of the repeated body in bytes
+    pub max_repeat_body_count: usize,
+    pub max_repeated_body_size: usize,
+    /// Largest accepted `internal.pad-bytes`
+    pub max_pad_bytes: usize,
+    /// File whose content replaces the echoed body
+    pub response_file: Option<String>,
+    /// File rendered as the response body, with request placeholders like `{method}` or `{header.<name>}`
+    pub template_file: Option<String>,
+    /// TOML or JSON file with canned responses by method and path
+    pub replay: Option<String>,
+    pub routes: Vec<RouteSettings>,
+    pub rules: Vec<RuleSettings>,
+    /// File to append a JSON line per echoed request to
+    pub record: Option<String>,
+    /// `user:pass` credentials required with HTTP Basic authentication on echoed requests
+    #[serde(serialize_with = "serialize_masked_credentials")]
+    pub auth: Option<String>,
+    /// Addresses or CIDR ranges clients must connect from, every client is allowed when empty
+    pub allow_ips: Vec<String>,
+    /// Check the last X-Forwarded-For entry against `allow_ips` instead of the peer address
+    pub trust_forwarded_for: bool,
+    /// Bearer token enabling POST /__shutdown, which stops the server gracefully
+    #[serde(serialize_with = "serialize_masked_credentials")]
+    pub shutdown_token: Option<String>,
+    /// Requests per second above which 429 Too Many Requests is returned
+    pub rate_limit: Option<u32>,
+    /// Apply the rate limit per client instead of globally
+    pub rate_limit_by_ip: bool,
+    pub compress: bool,
+    /// CORS is enabled when set
+    pub cors: Option<CorsSettings>,
+    /// Serve the WebSocket echo endpoint at /ws
+    pub websocket: bool,
+    /// Echo /favicon.ico requests like any other instead of answering them with 204 No Content
+    pub echo_favicon: bool,
+    /// Reject malformed internal headers with 400 instead of ignoring them
+    pub strict: bool,
+    /// Name the rule or route that decided the response in `X-Matched-Rule`, when there are any
+    pub matched_rule_header: bool,
+    /// Serve a single echoed request and reject the ones after it, see [`ServerHandle::request_served`]
+    pub once: bool,
+    /// Log requests and responses to stdout, or to `log_file` when set
+    pub verbose: bool,
+    pub log_format: LogFormat,
+    /// Whether the text verbose logging is colored
+    pub color: ColorMode,
+    pub log_file: Option<String>,
+    /// Log a line per request, separate from the verbose logging
+    pub access_log: bool,
+    /// Format of the access log lines, the actix default when not set
+    pub access_log_format: Option<AccessLogFormat>,
+}
+
+// Listens on a free port of 127.0.0.1 with a single worker, which suits tests
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            settings_path: None,
+            bind_addresses: vec![SocketAddr::from(([127, 0, 0, 1], 0))],
+            https_bind_addresses: Vec::new(),
+            uds: None,
+            dual_stack: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            h2c: false,
+            no_continue: false,
+            workers: 1,
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            keep_alive_secs: DEFAULT_KEEP_ALIVE_SECS,
+            max_connections: None,
+            backlog: None,
+            health_path: DEFAULT_HEALTH_PATH.to_string(),
+            base_path: String::new(),
+            reserved_headers: RESERVED_HEADERS.iter().map(|h| h.to_string()).collect(),
+            preserve_header_case: false,
+            server_timing: false,
+            default_format: ResponseFormat::default(),
+            default_status: StatusCode::OK.as_u16(),
+            default_content_type: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_delay_ms: MAX_DELAY_MS,
+            max_repeat_body_count: MAX_REPEAT_BODY_COUNT,
+            max_repeated_body_size: MAX_REPEATED_BODY_SIZE,
+            max_pad_bytes: DEFAULT_MAX_PAD_BYTES,
+            response_file: None,
+            template_file: None,
+            replay: None,
+            routes: Vec::new(),
+            rules: Vec::new(),
+            record: None,
+            auth: None,
+            allow_ips: Vec::new(),
+            trust_forwarded_for: false,
+            shutdown_token: None,
+            rate_limit: None,
+            rate_limit_by_ip: false,
+            compress: false,
+            cors: None,
+            websocket: false,
+            echo_favicon: false,
+            strict: false,
+            matched_rule_header: false,
+            once: false,
+            verbose: false,
+            log_format: LogFormat::default(),
+            color: ColorMode::default(),
+            log_file: None,
+            access_log: true,
+            access_log_format: None,
+        }
+    }
+}
+
+// Keeps the user name of `user:pass` credentials and hides the password
+fn mask_credentials(credentials: &str) -> String {
+    match credentials.split_once(':') {
+        Some((user, _)) => format!("{}:********", user),
+        None => "********".to_string(),
+    }
+}
+
+fn serialize_masked_credentials<S: serde::Serializer>(credentials: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    credentials.as_deref().map(mask_credentials).serialize(serializer)
+}
+
+// Only reached with a base path, for the requests outside of it
+async fn outside_base_path_handler(req: HttpRequest, config: web::Data<ServerConfig>) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("'{}' is outside of the base path '{}'", req.path(), config.base_path))
+}
+
+async fn config_handler(config: web::Data<ServerConfig>) -> HttpResponse {
+    HttpResponse::Ok().json(config.get_ref())
+}
+
+async fn metrics_handler(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+async fn stats_handler(stats: web::Data<Stats>) -> HttpResponse {
+    HttpResponse::Ok().json(stats.snapshot())
+}
+
+async fn reset_stats_handler(stats: web::Data<Stats>) -> HttpResponse {
+    stats.reset();
+    HttpResponse::NoContent().finish()
+}
+
+// Header names mapped to their values, repeated headers are joined with ", "
+fn headers_map(headers: &HeaderMap) -> BTreeMap<&str, String> {
+    let mut map: BTreeMap<&str, String> = BTreeMap::new();
+    for (name, value) in headers.iter() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        map.entry(name.as_str())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    map
+}
+
+/// Output format of the verbose request/response logging
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable INCOMING/OUTGOING blocks
+    #[default]
+    Text,
+    /// One JSON object per request
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format '{}'. Must be 'text' or 'json'.", s)),
+        }
+    }
+}
+
+/// When the text verbose logging is colored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// When logging to a terminal, unless the `NO_COLOR` environment variable is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(format!("Invalid color mode '{}'. Must be 'auto', 'always' or 'never'.", s)),
+        }
+    }
+}
+
+impl ColorMode {
+    // A --log-file is never a terminal
+    fn enabled(self, to_stdout: bool) -> bool {
+        use std::io::IsTerminal;
+
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => to_stdout && std::env::var_os(NO_COLOR_ENV_VAR).is_none() && std::io::stdout().is_terminal(),
+        }
+    }
+}
+
+// Wraps the text in an ANSI SGR sequence when coloring
+fn paint(color: bool, style: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+// Green for success, cyan for redirects, yellow for client errors and red for server errors
+fn status_style(status: u16) -> &'static str {
+    match status {
+        200..=299 => ANSI_GREEN,
+        300..=399 => ANSI_CYAN,
+        400..=499 => ANSI_YELLOW,
+        500..=599 => ANSI_RED,
+        _ => ANSI_BOLD,
+    }
+}
+
+/// Format of the access log lines written for every request
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Apache Common Log Format
+    Clf,
+    /// Apache Combined Log Format, CLF followed by the referer and user agent
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "clf" | "common" => Ok(AccessLogFormat::Clf),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!("Invalid access log format '{}'. Must be 'clf' or 'combined'.", s)),
+        }
+    }
+}
+
+// The actix default format when no access log format is given. `%t` is RFC 3339, so the CLF
+// timestamp is rendered by a custom replacement instead.
+fn access_logger(format: Option<AccessLogFormat>, echo_favicon: bool) -> Logger {
+    let format = match format {
+        None => None,
+        Some(AccessLogFormat::Clf) => Some(CLF_LOG_FORMAT.to_string()),
+        Some(AccessLogFormat::Combined) => Some(format!("{} \"%{{Referer}}i\" \"%{{User-Agent}}i\"", CLF_LOG_FORMAT)),
+    };
+    let logger = match format {
+        Some(format) => Logger::new(&format).custom_request_replace("clf_time", |_| clf_timestamp(SystemTime::now())),
+        None => Logger::default(),
+    };
+
+    // The favicon requests of browsers are noise unless they are echoed on purpose
+    if echo_favicon {
+        logger
+    } else {
+        logger.exclude(FAVICON_PATH)
+    }
+}
+
+// `10/Oct/2000:13:55:36 +0000`, rearranged from the `Tue, 10 Oct 2000 13:55:36 GMT` HTTP date
+fn clf_timestamp(time: SystemTime) -> String {
+    let date = actix_web::http::header::HttpDate::from(time).to_string();
+    match date.split_whitespace().collect::<Vec<_>>()[..] {
+        [_, day, month, year, time, _] => format!("{}/{}/{}:{} +0000", day, month, year, time),
+        _ => date,
+    }
+}
+
+// Where the verbose request/response logging goes: stdout, or the --log-file opened in append mode.
+// Every block is written and flushed at once, so blocks from concurrent requests don't interleave.
+#[derive(Debug, Default)]
+struct VerboseLog(Option<Mutex<std::io::BufWriter<std::fs::File>>>);
+
+impl VerboseLog {
+    fn open(path: &str) -> Result<Self, String> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(|file| VerboseLog(Some(Mutex::new(std::io::BufWriter::new(file)))))
+            .map_err(|e| format!("Could not open log file '{}': {}.", path, e))
+    }
+
+    fn write(&self, block: &str) {
+        let Some(writer) = &self.0 else {
+            print!("{}", block);
+            return;
+        };
+
+        if let Ok(mut writer) = writer.lock() {
+            if let Err(e) = writer.write_all(block.as_bytes()).and_then(|_| writer.flush()) {
+                eprintln!("Error: Could not write to log file: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonLogEntry<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    status: u16,
+    duration_ms: f64,
+    request_headers: BTreeMap<&'a str, String>,
+    request_body: String,
+    response_headers: BTreeMap<&'a str, String>,
+    response_body: String,
+}
+
+// Response body that fails as soon as it's polled. actix can't hand the socket to a handler,
+// but a failing body makes it drop the connection before the response is complete.
+struct AbortBody;
+
+impl MessageBody for AbortBody {
+    type Error = std::io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        Poll::Ready(Some(Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            format!("connection aborted by the '{}' header", INTERNAL_ABORT_HEADER),
+        ))))
+    }
+}
+
+// Body of a HEAD response: it advertises the length of the equivalent GET body in Content-Length
+// but never yields any data.
+struct HeadBody(u64);
+
+impl MessageBody for HeadBody {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.0)
+    }
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        Poll::Ready(None)
+    }
+}
+
+// Body of unknown size, so a Content-Length forced with `internal.content-length` is sent instead of
+// the real one
+struct UnsizedBody(Option<web::Bytes>);
+
+impl MessageBody for UnsizedBody {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        Poll::Ready(self.0.take().map(Ok))
+    }
+}
+
+// Body sent in `chunk_size` byte chunks with `interval` between them, for slow-read testing. The
+// length is known up front, so the client gets a Content-Length and just sees the bytes trickle in.
+struct DripBody {
+    body: web::Bytes,
+    chunk_size: usize,
+    interval: Duration,
+    sleep: Option<Pin<Box<actix_web::rt::time::Sleep>>>,
+}
+
+impl DripBody {
+    fn new(body: web::Bytes, interval: Duration) -> Self {
+        DripBody { chunk_size: drip_chunk_size(body.len()), body, interval, sleep: None }
+    }
+}
+
+impl MessageBody for DripBody {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.body.len() as u64)
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        if self.body.is_empty() {
+            return Poll::Ready(None);
+        }
+
+        // The first chunk goes out right away, the next ones after the interval
+        if let Some(sleep) = self.sleep.as_mut() {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        let interval = self.interval;
+        self.sleep = Some(Box::pin(actix_web::rt::time::sleep(interval)));
+        let chunk_size = self.chunk_size.min(self.body.len());
+        Poll::Ready(Some(Ok(self.body.split_to(chunk_size))))
+    }
+}
+
+// One byte per chunk, unless that would make more than MAX_DRIP_CHUNKS chunks
+fn drip_chunk_size(body_len: usize) -> usize {
+    body_len.div_ceil(MAX_DRIP_CHUNKS).max(1)
+}
+
+// Cookie name/value pairs of every Cookie header, the first of several cookies with the same name wins
+fn request_cookies(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut cookies = BTreeMap::new();
+    let pairs = headers
+        .get_all(actix_web::http::header::COOKIE)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.split_once('='));
+    for (name, value) in pairs {
+        let name = name.trim();
+        if !name.is_empty() {
+            cookies.entry(name.to_string()).or_insert_with(|| value.trim().to_string());
+        }
+    }
+    cookies
+}
+
+// Query parameters as a JSON object, a parameter given several times becomes an array of its values
+fn query_object(query_params: &[(String, String)]) -> serde_json::Map<String, serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for (name, value) in query_params {
+        let value = serde_json::Value::String(value.clone());
+        match object.get_mut(name) {
+            Some(serde_json::Value::Array(values)) => values.push(value),
+            Some(first) => *first = serde_json::Value::Array(vec![first.take(), value]),
+            None => {
+                object.insert(name.clone(), value);
+            }
+        }
+    }
+    object
+}
+
+// Names that aren't valid header values, like a regex with non-ASCII characters, are left out
+fn insert_matched_rule_header(response: &mut actix_web::HttpResponseBuilder, labels: &[&str]) {
+    if let Ok(value) = HeaderValue::from_str(&labels.join(", ")) {
+        response.insert_header((MATCHED_RULE_HEADER, value));
+    }
+}
+
+fn is_header_set(headers: &HeaderMap, name: &str, expected: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case(expected))
+}
+
+// Token bucket rate limiter: every key gets a bucket holding up to `limit` tokens, refilled at
+// `limit` tokens per second, and each request takes one token. This allows short bursts of up
+// to `limit` requests while keeping the average rate at `limit` requests per second.
+#[derive(Debug, Default)]
+struct RateLimiter {
+    limit: Option<u32>,
+    // Key buckets by the X-Real-IP header (or the peer address) instead of one global bucket
+    by_ip: bool,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// Above this many buckets, the ones that are full again are dropped
+const MAX_RATE_LIMIT_BUCKETS: usize = 10_000;
+
+impl RateLimiter {
+    fn new(limit: Option<u32>, by_ip: bool) -> Self {
+        RateLimiter {
+            limit,
+            by_ip,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(&self, req: &HttpRequest) -> String {
+        if !self.by_ip {
+            return String::new();
+        }
+
+        req.headers()
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .map(|ip| ip.trim().to_string())
+            .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
+            .unwrap_or_default()
+    }
+
+    // Takes a token for the key, or returns how long to wait until one is available
+    fn check(&self, key: &str) -> Result<(), Duration> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let rate = f64::from(limit);
+        let now = Instant::now();
+
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        if buckets.len() >= MAX_RATE_LIMIT_BUCKETS {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate < rate);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: rate,
+            updated_at: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * rate).min(rate);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    // Builds the 429 response if the request is over the limit
+    fn reject(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        let retry_after = self.check(&self.key(req)).err()?;
+        // Retry-After only has a one second resolution
+        let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        Some(
+            HttpResponse::TooManyRequests()
+                .insert_header((actix_web::http::header::RETRY_AFTER, retry_after_secs.to_string()))
+                .body("Rate limit exceeded"),
+        )
+    }
+}
+
+// Fixed response body loaded once at startup from --response-file
+#[derive(Debug, Default)]
+struct ResponseFile(Option<web::Bytes>);
+
+impl ResponseFile {
+    fn load(path: &str) -> Result<Self, String> {
+        std::fs::read(path)
+            .map(|content| ResponseFile(Some(web::Bytes::from(content))))
+            .map_err(|e| format!("Could not read response file '{}': {}.", path, e))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayFile {
+    #[serde(default)]
+    responses: Vec<ReplayEntry>,
+}
+
+// A canned response as written in the --replay file
+#[derive(Debug, Deserialize)]
+struct ReplayEntry {
+    // Matches any method when omitted
+    method: Option<String>,
+    path: String,
+    status: Option<u16>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Debug)]
+struct ReplayResponse {
+    method: Option<actix_web::http::Method>,
+    path: String,
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: web::Bytes,
+}
+
+// Canned responses loaded once at startup from --replay, the first entry matching the request's
+// method and path is served instead of the echo
+#[derive(Debug, Default)]
+struct Replay(Vec<ReplayResponse>);
+
+impl Replay {
+    // JSON when the file has a .json extension in any case, TOML otherwise
+    fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read replay file '{}': {}.", path, e))?;
+        let file: ReplayFile = if file_extension(path).as_deref() == Some("json") {
+            serde_json::from_str(&content).map_err(|e| format!("Invalid replay file '{}': {}.", path, e))?
+        } else {
+            toml::from_str(&content).map_err(|e| format!("Invalid replay file '{}': {}.", path, e))?
+        };
+        Self::from_entries(file.responses).map_err(|e| format!("Invalid replay file '{}': {}", path, e))
+    }
+
+    fn from_entries(entries: Vec<ReplayEntry>) -> Result<Self, String> {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let method = entry
+                    .method
+                    .map(|method| {
+                        actix_web::http::Method::from_str(&method.to_uppercase())
+                            .map_err(|_| format!("Invalid method '{}' for {}.", method, entry.path))
+                    })
+                    .transpose()?;
+                let status = match entry.status {
+                    Some(status) => validate_status(&status.to_string())?,
+                    None => StatusCode::OK,
+                };
+                let headers = entry
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        let name = HeaderName::from_str(name)
+                            .map_err(|_| format!("Invalid header name '{}' for {}.", name, entry.path))?;
+                        let value = HeaderValue::from_str(value)
+                            .map_err(|_| format!("Invalid value for header '{}' for {}.", name, entry.path))?;
+                        Ok((name, value))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+
+                Ok(ReplayResponse {
+                    method,
+                    path: entry.path,
+                    status,
+                    headers,
+                    body: web::Bytes::from(entry.body),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(Replay)
+    }
+
+    fn find(&self, req: &HttpRequest) -> Option<&ReplayResponse> {
+        self.0.iter().find(|response| {
+            response.path == req.path() && response.method.as_ref().is_none_or(|method| method == req.method())
+        })
+    }
+}
+
+impl ReplayResponse {
+    fn to_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        for (name, value) in &self.headers {
+            response.append_header((name.clone(), value.clone()));
+        }
+        response.body(self.body.clone())
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    pattern: String,
+    body: Option<web::Bytes>,
+    status: Option<StatusCode>,
+    // `routes[<index>] <name or path>`, for the MATCHED_RULE_HEADER
+    label: String,
+}
+
+// Routes from the settings, the first one whose glob matches the request path replaces the echoed
+// body and the default status. Internal headers still take precedence.
+#[derive(Debug, Default)]
+struct Routes(Vec<Route>);
+
+impl Routes {
+    fn new(routes: &[RouteSettings]) -> Result<Self, String> {
+        routes
+            .iter()
+            .enumerate()
+            .map(|(index, route)| {
+                let path = validate_path(&route.path)?;
+                let status = route
+                    .status
+                    .map(|status| validate_status(&status.to_string()))
+                    .transpose()
+                    .map_err(|e| format!("{} (route '{}')", e, route.path))?;
+                Ok(Route {
+                    label: format!("routes[{}] {}", index, route.name.as_deref().unwrap_or(&path)),
+                    pattern: path,
+                    body: route.body.clone().map(web::Bytes::from),
+                    status,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(Routes)
+    }
+
+    fn find(&self, path: &str) -> Option<&Route> {
+        self.0.iter().find(|route| glob_match(&route.pattern, path))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// `*` matches anything but a '/', `**` matches anything and `?` matches a single character
+fn glob_match(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[u8], path: &[u8]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            [b'*', b'*', rest @ ..] => (0..=path.len()).any(|skip| matches(rest, &path[skip..])),
+            [b'*', rest @ ..] => {
+                let segment_len = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+                (0..=segment_len).any(|skip| matches(rest, &path[skip..]))
+            }
+            [b'?', rest @ ..] => !path.is_empty() && path[0] != b'/' && matches(rest, &path[1..]),
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+
+    matches(pattern.as_bytes(), path.as_bytes())
+}
+
+#[derive(Debug)]
+enum RuleAction {
+    Echo,
+    Respond { status: Option<StatusCode>, body: web::Bytes },
+}
+
+#[derive(Debug)]
+struct Rule {
+    path_regex: Regex,
+    action: RuleAction,
+    // `rules[<index>] <name or regex>`, for the MATCHED_RULE_HEADER
+    label: String,
+}
+
+// Rules from the settings, compiled once at startup and evaluated in order against the request
+// path. The first matching one either answers with its canned response or lets the request be echoed.
+#[derive(Debug, Default)]
+struct Rules(Vec<Rule>);
+
+impl Rules {
+    fn new(rules: &[RuleSettings]) -> Result<Self, String> {
+        rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                let path_regex = Regex::new(&rule.path_regex)
+                    .map_err(|e| format!("Invalid path_regex '{}': {}", rule.path_regex, e))?;
+                let action = match (rule.echo, rule.status, &rule.body) {
+                    (true, None, None) => RuleAction::Echo,
+                    (true, _, _) => {
+                        return Err(format!("Rule '{}' can't both echo and set a status or body.", rule.path_regex));
+                    }
+                    (false, None, None) => {
+                        return Err(format!("Rule '{}' must set echo = true, a status or a body.", rule.path_regex));
+                    }
+                    (false, status, body) => RuleAction::Respond {
+                        status: status
+                            .map(|status| validate_status(&status.to_string()))
+                            .transpose()
+                            .map_err(|e| format!("{} (rule '{}')", e, rule.path_regex))?,
+                        body: web::Bytes::from(body.clone().unwrap_or_default()),
+                    },
+                };
+                let label = format!("rules[{}] {}", index, rule.name.as_deref().unwrap_or(&rule.path_regex));
+                Ok(Rule { path_regex, action, label })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(Rules)
+    }
+
+    fn find(&self, path: &str) -> Option<&Rule> {
+        self.0.iter().find(|rule| rule.path_regex.is_match(path))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Runtime options of the echo handlers, built once from the ServerConfig and shared by every worker
+#[derive(Debug)]
+struct Config {
+    verbose: bool,
+    log_format: LogFormat,
+    // Color the text verbose logging, resolved from the ColorMode
+    color: bool,
+    default_format: ResponseFormat,
+    // Status code used when a request doesn't override it, from --default-status
+    default_status: StatusCode,
+    // --default-content-type, set on echoed responses that have no Content-Type
+    default_content_type: Option<HeaderValue>,
+    reserved_headers: ReservedHeaders,
+    // --preserve-header-case: echoed responses are written with Title-Case header names
+    preserve_header_case: bool,
+    // --server-timing: echoed responses tell how long they took in a Server-Timing header
+    server_timing: bool,
+    // Largest request body accepted, larger ones are answered with 413 Payload Too Large
+    max_body_size: usize,
+    // Caps on the internal.delay-ms and internal.repeat-body controls
+    max_delay_ms: u64,
+    max_repeat_body_count: usize,
+    max_repeated_body_size: usize,
+    // --max-pad-bytes, larger internal.pad-bytes values are rejected
+    max_pad_bytes: usize,
+    // --strict: malformed internal headers are rejected with a problem+json body instead of ignored
+    strict: bool,
+    // --matched-rule-header, only when there are routes or rules
+    matched_rule_header: bool,
+    response_file: ResponseFile,
+    template_file: Option<Template>,
+    replay: Replay,
+    routes: Routes,
+    rules: Rules,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            verbose: false,
+            log_format: LogFormat::default(),
+            color: false,
+            default_format: ResponseFormat::default(),
+            default_status: StatusCode::OK,
+            default_content_type: None,
+            reserved_headers: ReservedHeaders::new(&ServerConfig::default().reserved_headers),
+            preserve_header_case: false,
+            server_timing: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_delay_ms: MAX_DELAY_MS,
+            max_repeat_body_count: MAX_REPEAT_BODY_COUNT,
+            max_repeated_body_size: MAX_REPEATED_BODY_SIZE,
+            max_pad_bytes: DEFAULT_MAX_PAD_BYTES,
+            strict: false,
+            matched_rule_header: false,
+            response_file: ResponseFile::default(),
+            template_file: None,
+            replay: Replay::default(),
+            routes: Routes::default(),
+            rules: Rules::default(),
+        }
+    }
+}
+
+impl Config {
+    fn payload_config(&self) -> web::PayloadConfig {
+        web::PayloadConfig::new(self.max_body_size)
+    }
+}
+
+// Echoes every text and binary message back to the sender until the client closes the connection
+async fn websocket_handler(req: HttpRequest, body: web::Payload) -> ActixResult<HttpResponse> {
+    let (response, mut session, stream) = actix_ws::handle(&req, body)?;
+    let mut stream = stream.aggregate_continuations();
+
+    actix_web::rt::spawn(async move {
+        while let Some(Ok(message)) = stream.recv().await {
+            let sent = match message {
+                AggregatedMessage::Text(text) => session.text(text).await,
+                AggregatedMessage::Binary(bytes) => session.binary(bytes).await,
+                AggregatedMessage::Ping(bytes) => session.pong(&bytes).await,
+                AggregatedMessage::Pong(_) => Ok(()),
+                AggregatedMessage::Close(reason) => {
+                    let _ = session.close(reason).await;
+                    return;
+                }
+            };
+            if sent.is_err() {
+                return;
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+async fn favicon_handler() -> HttpResponse {
+    HttpResponse::NoContent().finish()
+}
+
+async fn health_handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(actix_web::http::header::ContentType::json())
+        .body(r#"{"status":"ok"}"#)
+}
+
+// Streams the request body back as a chunked response while it is still being received, so large
+// bodies never have to be buffered. Selected with `internal.stream: true`, only the status code
+// override and header echoing apply since the other controls need the whole body.
+async fn stream_handler(
+    req: HttpRequest,
+    body: web::Payload,
+    config: web::Data<Config>,
+    stats: web::Data<Stats>,
+    rate_limiter: web::Data<RateLimiter>,
+) -> ActixResult<HttpResponse> {
+    stats.record(&req);
+
+    if let Some(response) = rate_limiter.reject(&req) {
+        return Ok(response);
+    }
+
+    let status_code = req
+        .headers()
+        .get(INTERNAL_STATUS_CODE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(config.default_status);
+
+    let mut response = HttpResponse::build(status_code);
+    for (name, value) in req.headers().iter() {
+        // The echoed body has no known length, so the request framing headers don't apply to it
+        let framing = name == actix_web::http::header::CONTENT_LENGTH || name == actix_web::http::header::TRANSFER_ENCODING;
+        if !framing && is_echoed_header(name.as_str(), &config.reserved_headers) {
+            response.append_header((name.clone(), value.clone()));
+        }
+    }
+
+    Ok(response.streaming(body))
+}
+
+fn is_stream_requested(ctx: &guard::GuardContext) -> bool {
+    is_header_set(ctx.head().headers(), INTERNAL_STREAM_HEADER, "true")
+}
+
+async fn echo_handler(
+    req: HttpRequest,
+    body: Result<web::Bytes, actix_web::Error>,
+    config: web::Data<Config>,
+    stats: web::Data<Stats>,
+    rate_limiter: web::Data<RateLimiter>,
+    recorder: web::Data<Recorder>,
+    verbose_log: web::Data<VerboseLog>,
+) -> ActixResult<HttpResponse> {
+    let started_at = Instant::now();
+    let headers = req.headers();
+    let self_check = is_self_check(headers);
+    if !self_check {
+        stats.record(&req);
+    }
+
+    let body = match body {
+        Ok(body) => body,
+        Err(e) if e.as_response_error().status_code() == actix_web::http::StatusCode::PAYLOAD_TOO_LARGE => {
+            return Ok(HttpResponse::PayloadTooLarge()
+                .body(format!("Request body exceeds the maximum size of {} bytes", config.max_body_size)));
+        }
+        Err(e) => return Err(e),
+    };
+    if !self_check {
+        recorder.record(&req, &body);
+    }
+
+    if let Some(response) = rate_limiter.reject(&req) {
+        return Ok(response);
+    }
+
+    // Log incoming request if verbose mode is enabled
+    if config.verbose && config.log_format == LogFormat::Text {
+        let color = config.color;
+        let mut block = String::new();
+        let _ = writeln!(block, "\n📥 {}", paint(color, ANSI_BOLD, "INCOMING REQUEST:"));
+        let _ = writeln!(block, "   {} {}{}", paint(color, ANSI_CYAN, req.method().as_str()), req.path(), req.query_string());
+        if !headers.is_empty() {
+            let _ = writeln!(block, "   Headers:");
+            for (name, value) in headers.iter() {
+                if let Ok(value_str) = value.to_str() {
+                    let _ = writeln!(block, "     {}: {}", paint(color, ANSI_DIM, name.as_str()), value_str);
+                }
+            }
+        } else {
+            let _ = writeln!(block, "   No headers");
+        }
+
+        if !body.is_empty() {
+            let _ = writeln!(block, "   Body: {}", String::from_utf8_lossy(&body));
+        }
+        verbose_log.write(&block);
+    }
+
+    // Serve the canned response for this method and path, if there is one
+    if let Some(replayed) = config.replay.find(&req) {
+        return Ok(replayed.to_response());
+    }
+
+    // The first matching rule answers with its canned response, or lets the request be echoed
+    let rule = config.rules.find(req.path());
+    if let Some(Rule { action: RuleAction::Respond { status, body }, label, .. }) = rule {
+        let mut response = HttpResponse::build(status.unwrap_or(config.default_status));
+        if config.matched_rule_header {
+            insert_matched_rule_header(&mut response, &[label]);
+        }
+        return Ok(response.body(body.clone()));
+    }
+
+    // In strict mode malformed internal controls are reported instead of being ignored
+    if config.strict {
+        let errors = malformed_internal_controls(&req);
+        if !errors.is_empty() {
+            return Ok(ProblemDetails::bad_request("One or more internal controls are malformed", errors));
+        }
+    }
+
+    // Check for internal response delay, optionally randomized by a jitter
+    let millis = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).and_then(|s| s.parse::<u64>().ok());
+    let delay_ms = millis(INTERNAL_DELAY_MS_HEADER);
+    let jitter_ms = millis(INTERNAL_DELAY_JITTER_MS_HEADER);
+    let mut applied_delay_ms = None;
+    if delay_ms.is_some() || jitter_ms.is_some() {
+        let delay_ms = match validate_delay_ms(delay_ms.unwrap_or(0), config.max_delay_ms) {
+            Ok(delay_ms) => delay_ms,
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        };
+        let jitter_ms = match validate_delay_jitter_ms(jitter_ms.unwrap_or(0), config.max_delay_ms) {
+            Ok(jitter_ms) => jitter_ms,
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        };
+        let delay_ms = jittered_delay_ms(delay_ms, jitter_ms, config.max_delay_ms);
+        actix_web::rt::time::sleep(Duration::from_millis(delay_ms)).await;
+        applied_delay_ms = Some(delay_ms);
+    }
+
+    // Hang up without a complete response, to simulate a flaky upstream
+    if is_header_set(headers, INTERNAL_ABORT_HEADER, "true") {
+        return Ok(HttpResponse::Ok().force_close().body(AbortBody));
+    }
+
+    let query_params = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+
+    // Pick a status from the random status set, a fresh choice for every request
+    let random_status = match headers.get(INTERNAL_RANDOM_STATUS_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(value) => match parse_status_list(value) {
+            // The thread local RNG is seeded separately for every worker thread
+            Ok(statuses) => statuses.choose(&mut rand::rng()).map(StatusCode::as_u16),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        None => None,
+    };
+
+    // The response always has the version of the request, so a different one can only be refused
+    if let Some(version) = headers.get(INTERNAL_HTTP_VERSION_HEADER).and_then(|v| v.to_str().ok()) {
+        if let Err(e) = parse_http_version(version).and_then(|version| check_http_version(version, req.version())) {
+            return Ok(bad_request(config.strict, e));
+        }
+    }
+
+    let route = config.routes.find(req.path());
+
+    // A `/status/<code>` path sets the status on its own, an invalid code is always rejected
+    let path_status = match path_status(&req) {
+        Some(Ok(status)) => Some(status.as_u16()),
+        Some(Err(e)) => return Ok(bad_request(config.strict, e)),
+        None => None,
+    };
+
+    // Check for internal status code override, the headers take precedence over the query parameter
+    let status_code = path_status
+        .or_else(|| {
+            headers
+                .get(INTERNAL_STATUS_CODE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|s| s.parse::<u16>().ok())
+        })
+        .or(random_status)
+        .or_else(|| {
+            query_params
+                .iter()
+                .find(|(name, _)| name == QUERY_STATUS_PARAM)
+                .and_then(|(_, value)| value.parse::<u16>().ok())
+        })
+        .or_else(|| route.and_then(|route| route.status).map(|status| status.as_u16()))
+        .unwrap_or(config.default_status.as_u16());
+
+    // Check for internal response format override
+    let response_format = headers
+        .get(INTERNAL_FORMAT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.parse::<ResponseFormat>().ok())
+        .unwrap_or(config.default_format);
+
+    // Check for internal body repetition
+    let repeat_count = headers
+        .get(INTERNAL_REPEAT_BODY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<usize>().ok());
+    let repeat_count = repeat_count.map(|count| {
+        validate_repeat_count(count, body.len(), config.max_repeat_body_count, config.max_repeated_body_size)
+    });
+    let repeat_count = match repeat_count {
+        Some(Ok(count)) => count,
+        Some(Err(e)) => return Ok(bad_request(config.strict, e)),
+        None => 1,
+    };
+
+    // Generated padding replaces the body, for download tests without a large request
+    let pad_bytes = headers
+        .get(INTERNAL_PAD_BYTES_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<usize>().ok());
+    let pad_bytes = match pad_bytes.map(|length| validate_pad_bytes(length, config.max_pad_bytes)) {
+        Some(Ok(length)) => Some(length),
+        Some(Err(e)) => return Ok(bad_request(config.strict, e)),
+        None => None,
+    };
+
+    // Redirect instead of echoing when a 3xx status comes with a location
+    let location = match headers.get(INTERNAL_LOCATION_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(location) if (300..400).contains(&status_code) => match validate_location(location) {
+            Ok(location) => Some(location),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        _ => None,
+    };
+
+    // Retry-After only makes sense on 503 Service Unavailable and 429 Too Many Requests
+    let retry_after = match headers.get(INTERNAL_RETRY_AFTER_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(retry_after) if status_code == 503 || status_code == 429 => match validate_retry_after(retry_after) {
+            Ok(retry_after) => Some(retry_after),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        _ => None,
+    };
+
+    // Cache-Control can't be echoed since it is a reserved request header
+    let cache_control = match headers.get(INTERNAL_CACHE_CONTROL_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(cache_control) => match validate_cache_control(cache_control) {
+            Ok(cache_control) => Some(cache_control),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        None => None,
+    };
+
+    // Content-Length that doesn't have to match the body, for negative testing of clients
+    let forced_content_length = match headers.get(INTERNAL_CONTENT_LENGTH_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(content_length) => match validate_content_length(content_length) {
+            Ok(content_length) => Some(content_length),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        None => None,
+    };
+
+    // Trailers need a chunked body, which HTTP/1.0 doesn't have and HTTP/2 frames by itself
+    let trailers = match parse_trailers(headers.get_all(INTERNAL_TRAILER_HEADER).filter_map(|v| v.to_str().ok())) {
+        Ok(trailers) if req.version() == actix_web::http::Version::HTTP_11 => trailers,
+        Ok(_) => Vec::new(),
+        Err(e) => return Ok(bad_request(config.strict, e)),
+    };
+
+    // Collect the cookies to set, every `internal.set-cookie` value can hold several of them
+    let cookies = match parse_set_cookies(headers.get_all(INTERNAL_SET_COOKIE_HEADER).filter_map(|v| v.to_str().ok())) {
+        Ok(cookies) => cookies,
+        Err(e) => return Ok(bad_request(config.strict, e)),
+    };
+
+    // Collect the response headers to send several times
+    let duplicated_headers = headers
+        .get_all(INTERNAL_DUPLICATE_HEADER_HEADER)
+        .filter_map(|v| v.to_str().ok())
+        .map(parse_header_duplicate)
+        .collect::<Result<Vec<_>, _>>();
+    let duplicated_headers = match duplicated_headers {
+        Ok(duplicated_headers) => duplicated_headers,
+        Err(e) => return Ok(bad_request(config.strict, e)),
+    };
+
+    // Collect the raw `Name: Value` response headers to add
+    let added_headers = headers.get_all(INTERNAL_ADD_HEADER_HEADER).filter_map(|v| v.to_str().ok());
+    let added_headers = match parse_added_headers(INTERNAL_ADD_HEADER_HEADER, added_headers) {
+        Ok(added_headers) => added_headers,
+        Err(e) => return Ok(bad_request(config.strict, e)),
+    };
+
+    // Respond with the whole request as text, reserved headers included
+    let raw = is_header_set(headers, INTERNAL_RAW_HEADER, "true");
+
+    // Respond with the cookies the client sent, although the cookie header is reserved
+    let reflect_cookies = is_header_set(headers, INTERNAL_REFLECT_COOKIES_HEADER, "true");
+
+    // Respond with the parsed query string
+    let reflect_query = is_header_set(headers, INTERNAL_REFLECT_QUERY_HEADER, "true");
+
+    // Respond with a checksum of the body instead of the body
+    let checksum = match headers.get(INTERNAL_CHECKSUM_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(algorithm) => match algorithm.parse::<ChecksumAlgorithm>() {
+            Ok(algorithm) => Some(algorithm.hex_digest(&body)),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        None => None,
+    };
+
+    // Parsed up front since it's the only format that can reject the body
+    let multipart_summary = match response_format {
+        ResponseFormat::MultipartSummary => match MultipartSummary::from_request(&req, body.clone()).await {
+            Ok(summary) => Some(summary),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        _ => None,
+    };
+
+    // Binary bodies through a header, invalid base64 falls back to the other overrides
+    let decoded_body = headers
+        .get(INTERNAL_RESPONSE_BODY_BASE64_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|encoded| decode_response_body_base64(encoded).ok());
+
+    // Check for internal response body override, then for the template and fixed response files
+    let response_body = match headers.get(INTERNAL_RESPONSE_BODY_HEADER).and_then(|v| v.to_str().ok()) {
+        _ if location.is_some() => web::Bytes::new(),
+        _ if raw => raw_request_text(&req, &body),
+        _ if checksum.is_some() => web::Bytes::from(checksum.clone().unwrap_or_default()),
+        _ if reflect_cookies => web::Bytes::from(serde_json::to_vec(&request_cookies(headers))?),
+        _ if reflect_query => web::Bytes::from(serde_json::to_vec(&query_object(&query_params))?),
+        _ if pad_bytes.is_some() => pad_body(pad_bytes.unwrap_or_default()),
+        _ if decoded_body.is_some() => web::Bytes::from(decoded_body.unwrap_or_default()),
+        Some(s) => web::Bytes::from(s.to_string()),
+        None if headers.contains_key(INTERNAL_TEMPLATE_HEADER) => {
+            let template = headers.get(INTERNAL_TEMPLATE_HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+            web::Bytes::from(render_template(template, &req, &body))
+        }
+        // A route's body takes precedence over the template file
+        None if config.template_file.is_some() && route.and_then(|route| route.body.as_ref()).is_none() => {
+            let (rendered, errors) = config.template_file.as_ref().map(|template| template.render(&req, &body)).unwrap_or_default();
+            if config.strict && !errors.is_empty() {
+                return Ok(ProblemDetails::internal_server_error("The template file can't be rendered for this request", errors));
+            }
+            web::Bytes::from(rendered)
+        }
+        None => match (route.and_then(|route| route.body.as_ref()).or(config.response_file.0.as_ref()), response_format) {
+            (Some(content), _) => content.clone(),
+            // Echo the raw bytes so binary payloads come back untouched
+            (None, ResponseFormat::Echo) if repeat_count == 1 => body.clone(),
+            (None, ResponseFormat::Echo) => web::Bytes::from(body.repeat(repeat_count)),
+            (None, ResponseFormat::Json) => web::Bytes::from(serde_json::to_vec(&JsonEcho::from_request(&req, &body))?),
+            (None, ResponseFormat::MultipartSummary) => web::Bytes::from(serde_json::to_vec(&multipart_summary)?),
+            (None, ResponseFormat::Hexdump) => web::Bytes::from(hexdump(&body)),
+        },
+    };
+
+    // Keep only a prefix of the body, a limit beyond its length changes nothing. Content-Length
+    // follows, so the response is complete, just shorter.
+    let truncate_length = headers
+        .get(INTERNAL_TRUNCATE_BODY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<usize>().ok());
+    let response_body = match truncate_length {
+        Some(length) if length < response_body.len() => response_body.slice(..length),
+        _ => response_body,
+    };
+
+    // Encode the body whatever the client accepts, before it's dripped or its length is taken
+    let forced_encoding = match headers.get(INTERNAL_FORCE_ENCODING_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(encoding) => match encoding.parse::<ForcedEncoding>() {
+            Ok(encoding) => Some(encoding),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        None => None,
+    };
+    let response_body = match forced_encoding {
+        Some(encoding) => encoding.encode(&response_body)?,
+        None => response_body,
+    };
+
+    // Send the body slowly, after its size is known so the total time can be capped
+    let drip_ms = match millis(INTERNAL_DRIP_MS_HEADER) {
+        Some(drip_ms) => match validate_drip_ms(drip_ms, response_body.len(), config.max_delay_ms) {
+            Ok(drip_ms) => Some(drip_ms),
+            Err(e) => return Ok(bad_request(config.strict, e)),
+        },
+        None => None,
+    };
+
+    // Create response with the determined status code
+    let mut response = HttpResponse::build(
+        StatusCode::from_u16(status_code)
+            .unwrap_or(config.default_status)
+    );
+
+    // Only echo the listed headers when an allowlist is given
+    let echo_allowlist = headers
+        .get(INTERNAL_ECHO_HEADERS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(parse_header_list);
+
+    // Copy non-reserved headers to response, excluding internal headers
+    for (name, value) in headers.iter() {
+        let allowed = echo_allowlist.as_ref().is_none_or(|allowlist| allowlist.contains(name.as_str()));
+        if allowed && is_echoed_header(name.as_str(), &config.reserved_headers) {
+            if let Ok(header_value) = value.to_str() {
+                response.insert_header((name.clone(), header_value));
+            }
+        }
+    }
+
+    // Copy request headers under new names, invalid entries are ignored (rejected in strict mode)
+    for (old_name, new_name) in headers
+        .get_all(INTERNAL_RENAME_HEADER_HEADER)
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|entry| parse_header_rename(entry).ok())
+    {
+        for value in headers.get_all(&old_name) {
+            response.append_header((new_name.clone(), value.clone()));
+        }
+    }
+
+    // Add response headers requested through `h_<name>=<value>` query parameters
+    for (name, value) in query_headers(&query_params) {
+        response.insert_header((name, value));
+    }
+
+    // Added headers replace echoed ones with the same name, repeating a name adds several values
+    let mut added_names = HashSet::new();
+    for (name, value) in added_headers {
+        if added_names.insert(name.clone()) {
+            response.insert_header((name, value));
+        } else {
+            response.append_header((name, value));
+        }
+    }
+
+    // Replaces an echoed header of the same name, so clients can't fake it
+    if let Some(ClientCertSubject(subject)) = req.conn_data::<ClientCertSubject>() {
+        if let Ok(subject) = HeaderValue::from_str(subject) {
+            response.insert_header((CLIENT_CERT_SUBJECT_HEADER, subject));
+        }
+    }
+
+    // The socket's peer, whatever X-Forwarded-For says. Unix domain sockets have none.
+    if is_header_set(headers, INTERNAL_REFLECT_PEER_HEADER, "true") {
+        if let Some(peer_addr) = req.peer_addr() {
+            response.insert_header((PEER_ADDR_HEADER, peer_addr.to_string()));
+        }
+    }
+
+    if let Some(checksum) = &checksum {
+        response.insert_header((BODY_CHECKSUM_HEADER, checksum.as_str()));
+    }
+
+    // An echoing rule and a route can both apply
+    if config.matched_rule_header {
+        let labels: Vec<&str> = rule.iter().map(|rule| rule.label.as_str()).chain(route.iter().map(|route| route.label.as_str())).collect();
+        insert_matched_rule_header(&mut response, if labels.is_empty() { &[MATCHED_RULE_ECHO] } else { &labels });
+    }
+
+    if raw || checksum.is_some() || pad_bytes.is_some() || response_format == ResponseFormat::Hexdump {
+        response.insert_header(actix_web::http::header::ContentType::plaintext());
+    } else if reflect_cookies || reflect_query || response_format != ResponseFormat::Echo {
+        response.insert_header(actix_web::http::header::ContentType::json());
+    }
+
+    // Override the echoed Content-Type, ignoring values that aren't valid MIME types
+    let content_type = headers
+        .get(INTERNAL_CONTENT_TYPE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_content_type);
+    if let Some(content_type) = content_type {
+        response.insert_header((actix_web::http::header::CONTENT_TYPE, content_type.to_string()));
+    }
+
+    if let Some(location) = location {
+        response.insert_header((actix_web::http::header::LOCATION, location));
+    }
+
+    if let Some(retry_after) = retry_after {
+        response.insert_header((actix_web::http::header::RETRY_AFTER, retry_after));
+    }
+
+    if let Some(cache_control) = cache_control {
+        response.insert_header((actix_web::http::header::CACHE_CONTROL, cache_control));
+    }
+
+    for cookie in &cookies {
+        response.cookie(cookie.clone());
+    }
+
+    // Already encoded, which also keeps --compress from encoding it a second time
+    if let Some(encoding) = forced_encoding {
+        response.insert_header((actix_web::http::header::CONTENT_ENCODING, encoding.name()));
+    }
+
+    // Measured last, so the total covers everything but sending the body
+    if config.server_timing {
+        response.insert_header((SERVER_TIMING_HEADER, server_timing(started_at.elapsed(), applied_delay_ms)));
+    }
+
+    // Close the connection once the response is sent
+    if is_header_set(headers, INTERNAL_CONNECTION_HEADER, "close") {
+        response.force_close();
+    }
+
+    // Bytes are reference counted, so keeping a copy for logging is cheap. HEAD gets the status
+    // and headers of the equivalent GET without the body.
+    let mut response = if req.method() == actix_web::http::Method::HEAD {
+        response.body(HeadBody(forced_content_length.unwrap_or(response_body.len() as u64)))
+    } else if let Some(content_length) = forced_content_length {
+        // The body is written as is after the forced Content-Length, and since the client can't
+        // tell where the response ends the connection is closed after it
+        response
+            .no_chunking(content_length)
+            .force_close()
+            .body(UnsizedBody(Some(response_body.clone())))
+    } else if !trailers.is_empty() {
+        let names = trailers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ");
+        response
+            .insert_header((actix_web::http::header::TRAILER, names))
+            .insert_header((actix_web::http::header::TRANSFER_ENCODING, "chunked"))
+            // Keeps --compress from encoding the already chunked body
+            .insert_header((actix_web::http::header::CONTENT_ENCODING, forced_encoding.map_or("identity", |e| e.name())));
+        let mut response = response.body(UnsizedBody(Some(chunked_with_trailers(&response_body, &trailers))));
+        // The body is chunked by hand, actix must write it as is
+        response.head_mut().no_chunking(true);
+        response
+    } else if let Some(drip_ms) = drip_ms {
+        response
+            // Compression would buffer the chunks and defeat the pacing
+            .insert_header((actix_web::http::header::CONTENT_ENCODING, forced_encoding.map_or("identity", |e| e.name())))
+            .body(DripBody::new(response_body.clone(), Duration::from_millis(drip_ms)))
+    } else {
+        response.body(response_body.clone())
+    };
+
+    // Neither the request nor an internal control gave the response a Content-Type
+    if let Some(content_type) = &config.default_content_type {
+        if !response.headers().contains_key(actix_web::http::header::CONTENT_TYPE) {
+            response.headers_mut().insert(actix_web::http::header::CONTENT_TYPE, content_type.clone());
+        }
+    }
+
+    // Done on the finished response so every header can be repeated, whatever set it
+    for (name, count) in duplicated_headers {
+        let values = response.headers().get_all(&name).cloned().collect::<Vec<_>>();
+        for _ in 1..count {
+            for value in &values {
+                response.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+    }
+    let elapsed = started_at.elapsed();
+
+    // Log outgoing response if verbose mode is enabled
+    if config.verbose && config.log_format == LogFormat::Json {
+        let entry = JsonLogEntry {
+            method: req.method().as_str(),
+            path: req.path(),
+            query: req.query_string(),
+            status: response.status().as_u16(),
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            request_headers: headers_map(headers),
+            request_body: String::from_utf8_lossy(&body).to_string(),
+            response_headers: headers_map(response.headers()),
+            response_body: String::from_utf8_lossy(&response_body).to_string(),
+        };
+        match serde_json::to_string(&entry) {
+            Ok(line) => verbose_log.write(&format!("{}\n", line)),
+            Err(e) => eprintln!("Error: Could not serialize log entry: {}", e),
+        }
+    } else if config.verbose {
+        let color = config.color;
+        let status = response.status().as_u16();
+        let mut block = String::new();
+        let _ = writeln!(block, "\n📤 {}", paint(color, ANSI_BOLD, "OUTGOING RESPONSE:"));
+        let _ = writeln!(block, "   Status: {}", paint(color, status_style(status), &status.to_string()));
+        let _ = writeln!(block, "   Duration: {:.3}ms", elapsed.as_secs_f64() * 1000.0);
+        let _ = writeln!(block, "   Headers:");
+        for (name, value) in response.headers().iter() {
+            if let Ok(header_value) = value.to_str() {
+                let _ = writeln!(block, "     {}: {}", paint(color, ANSI_DIM, name.as_str()), header_value);
+            }
+        }
+        let _ = writeln!(block, "   Body: {}", String::from_utf8_lossy(&response_body));
+        let _ = writeln!(block);
+        verbose_log.write(&block);
+    }
+
+    Ok(response)
+}
+
+// Text reconstruction of the request: request line, every header, a blank line and the body. Header
+// names come lowercased and, since the header map doesn't keep them, not in the order they were sent.
+fn raw_request_text(req: &HttpRequest, body: &[u8]) -> web::Bytes {
+    let mut raw = Vec::with_capacity(body.len() + 512);
+    raw.extend_from_slice(req.method().as_str().as_bytes());
+    raw.push(b' ');
+    raw.extend_from_slice(req.path().as_bytes());
+    if !req.query_string().is_empty() {
+        raw.push(b'?');
+        raw.extend_from_slice(req.query_string().as_bytes());
+    }
+    raw.extend_from_slice(format!(" {:?}\r\n", req.version()).as_bytes());
+    for (name, value) in req.headers().iter() {
+        raw.extend_from_slice(name.as_str().as_bytes());
+        raw.extend_from_slice(b": ");
+        raw.extend_from_slice(value.as_bytes());
+        raw.extend_from_slice(b"\r\n");
+    }
+    raw.extend_from_slice(b"\r\n");
+    raw.extend_from_slice(body);
+    web::Bytes::from(raw)
+}
+
+// Reserved headers and internal control headers are never echoed back
+fn is_echoed_header(name: &str, reserved_headers: &ReservedHeaders) -> bool {
+    !reserved_headers.contains(name) && !name.to_lowercase().starts_with(INTERNAL_HEADER_PREFIX)
+}
+
+// Query parameters that request a response header, skipping names or values that aren't valid in a header
+fn query_headers(query_params: &[(String, String)]) -> Vec<(HeaderName, HeaderValue)> {
+    query_params
+        .iter()
+        .filter_map(|(name, value)| {
+            let header_name = HeaderName::from_str(name.strip_prefix(QUERY_HEADER_PREFIX)?).ok()?;
+            let header_value = HeaderValue::from_str(value).ok()?;
+            Some((header_name, header_value))
+        })
+        .collect()
+}
+
+// Lowercased header names from a comma-separated list
+fn parse_header_list(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn parse_set_cookies<'a>(values: impl Iterator<Item = &'a str>) -> Result<Vec<Cookie<'static>>, String> {
+    values
+        .flat_map(|value| value.split(SET_COOKIE_DELIMITER))
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty())
+        .map(|cookie| {
+            Cookie::parse(cookie.to_string())
+                .map_err(|e| format!("Invalid cookie '{}' in {}: {}.", cookie, INTERNAL_SET_COOKIE_HEADER, e))
+        })
+        .collect()
+}
+
+// `Name: Value` entries of `header`, like `internal.add-header`
+fn parse_added_headers<'a>(
+    header: &str,
+    values: impl Iterator<Item = &'a str>,
+) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    values
+        .map(|entry| {
+            let invalid = || format!("Invalid {} '{}'. Use the 'Name: Value' format.", header, entry);
+            let (name, value) = entry.split_once(':').ok_or_else(invalid)?;
+            let name = HeaderName::from_str(name.trim()).map_err(|_| invalid())?;
+            let value = HeaderValue::from_str(value.trim()).map_err(|_| invalid())?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+// Framing headers can't be trailers, see RFC 9110 section 6.5.1
+fn parse_trailers<'a>(values: impl Iterator<Item = &'a str>) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    let trailers = parse_added_headers(INTERNAL_TRAILER_HEADER, values)?;
+    let framing = [
+        actix_web::http::header::CONTENT_LENGTH,
+        actix_web::http::header::TRANSFER_ENCODING,
+        actix_web::http::header::TRAILER,
+        actix_web::http::header::HOST,
+    ];
+    if let Some((name, _)) = trailers.iter().find(|(name, _)| framing.contains(name)) {
+        return Err(format!("Invalid {} '{}'. This header can't be sent as a trailer.", INTERNAL_TRAILER_HEADER, name));
+    }
+
+    Ok(trailers)
+}
+
+// Chunked encoding of the body followed by the trailers, since actix doesn't send trailers itself
+fn chunked_with_trailers(body: &[u8], trailers: &[(HeaderName, HeaderValue)]) -> web::Bytes {
+    let mut encoded = Vec::with_capacity(body.len() + 64);
+    if !body.is_empty() {
+        encoded.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        encoded.extend_from_slice(body);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        encoded.extend_from_slice(name.as_str().as_bytes());
+        encoded.extend_from_slice(b": ");
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"\r\n");
+    web::Bytes::from(encoded)
+}
+
+fn parse_status_list(value: &str) -> Result<Vec<StatusCode>, String> {
+    let statuses = value
+        .split(',')
+        .map(str::trim)
+        .filter(|status| !status.is_empty())
+        .map(validate_status)
+        .collect::<Result<Vec<_>, String>>()?;
+    if statuses.is_empty() {
+        return Err(format!("{} must list at least one status code.", INTERNAL_RANDOM_STATUS_HEADER));
+    }
+
+    Ok(statuses)
+}
+
+// Substitutes `{method}`, `{path}`, `{query}`, `{body}`, `{header.<name>}` and `{query.<name>}`
+// placeholders. Missing headers and query parameters become empty, unknown placeholders are kept
+// as is and `{{` / `}}` produce literal braces.
+fn render_template(template: &str, req: &HttpRequest, body: &[u8]) -> String {
+    Template::parse(template).render(req, body).0
+}
+
+// A response body template, parsed once into text and placeholders so the --template-file isn't
+// parsed for every request. Rendering only substitutes request values, nothing is evaluated.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Template(Vec<TemplatePart>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Text(String),
+    Method,
+    Path,
+    Query,
+    Body,
+    Header(String),
+    QueryParam(String),
+}
+
+impl Template {
+    fn load(path: &str) -> Result<Self, String> {
+        std::fs::read_to_string(path)
+            .map(|template| Template::parse(&template))
+            .map_err(|e| format!("Could not read template file '{}': {}.", path, e))
+    }
+
+    fn parse(template: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                text.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+
+            let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) else {
+                text.push_str(&rest[..1]);
+                rest = &rest[1..];
+                continue;
+            };
+            let placeholder = &rest[1..end];
+            let part = match placeholder {
+                "method" => Some(TemplatePart::Method),
+                "path" => Some(TemplatePart::Path),
+                "query" => Some(TemplatePart::Query),
+                "body" => Some(TemplatePart::Body),
+                _ => placeholder
+                    .strip_prefix("header.")
+                    .map(|name| TemplatePart::Header(name.to_string()))
+                    .or_else(|| placeholder.strip_prefix("query.").map(|name| TemplatePart::QueryParam(name.to_string()))),
+            };
+            match part {
+                Some(part) => {
+                    if !text.is_empty() {
+                        parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                }
+                None => text.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(TemplatePart::Text(text));
+        }
+
+        Template(parts)
+    }
+
+    // The rendered body, and what couldn't be substituted faithfully: missing headers and query
+    // parameters (rendered empty) and a body that isn't UTF-8 (rendered lossily)
+    fn render(&self, req: &HttpRequest, body: &[u8]) -> (String, Vec<String>) {
+        let query_params = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+            .map(|q| q.into_inner())
+            .unwrap_or_default();
+
+        let mut rendered = String::new();
+        let mut errors = Vec::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Text(text) => rendered.push_str(text),
+                TemplatePart::Method => rendered.push_str(req.method().as_str()),
+                TemplatePart::Path => rendered.push_str(req.path()),
+                TemplatePart::Query => rendered.push_str(req.query_string()),
+                TemplatePart::Body => {
+                    if std::str::from_utf8(body).is_err() {
+                        errors.push("'{body}': The request body is not valid UTF-8.".to_string());
+                    }
+                    rendered.push_str(&String::from_utf8_lossy(body));
+                }
+                TemplatePart::Header(name) => match req.headers().get(name.as_str()).and_then(|v| v.to_str().ok()) {
+                    Some(value) => rendered.push_str(value),
+                    None => errors.push(format!("'{{header.{}}}': The request has no '{}' header.", name, name)),
+                },
+                TemplatePart::QueryParam(name) => match query_params.iter().find(|(param, _)| param == name) {
+                    Some((_, value)) => rendered.push_str(value),
+                    None => errors.push(format!("'{{query.{}}}': The request has no '{}' query parameter.", name, name)),
+                },
+            }
+        }
+
+        (rendered, errors)
+    }
+}
+
+fn parse_header_rename(entry: &str) -> Result<(HeaderName, HeaderName), String> {
+    let invalid = || format!("Invalid {} '{}'. Use the 'old:new' format.", INTERNAL_RENAME_HEADER_HEADER, entry);
+    let (old_name, new_name) = entry.split_once(':').ok_or_else(invalid)?;
+    let old_name = HeaderName::from_str(old_name.trim()).map_err(|_| invalid())?;
+    let new_name = HeaderName::from_str(new_name.trim()).map_err(|_| invalid())?;
+    Ok((old_name, new_name))
+}
+
+// `name:count`, the count being how many times the header is sent in total
+fn parse_header_duplicate(entry: &str) -> Result<(HeaderName, usize), String> {
+    let invalid = || format!("Invalid '{}' value '{}'. Must be in the 'name:count' format.", INTERNAL_DUPLICATE_HEADER_HEADER, entry);
+    let (name, count) = entry.rsplit_once(':').ok_or_else(invalid)?;
+    let name = HeaderName::from_str(name.trim()).map_err(|_| invalid())?;
+    let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+    if !(1..=MAX_HEADER_DUPLICATES).contains(&count) {
+        return Err(format!(
+            "Invalid '{}' value '{}'. The count must be between 1 and {}.",
+            INTERNAL_DUPLICATE_HEADER_HEADER, entry, MAX_HEADER_DUPLICATES
+        ));
+    }
+    Ok((name, count))
+}
+
+fn parse_http_version(value: &str) -> Result<actix_web::http::Version, String> {
+    match value.trim() {
+        "1.0" => Ok(actix_web::http::Version::HTTP_10),
+        "1.1" => Ok(actix_web::http::Version::HTTP_11),
+        _ => Err(format!("Invalid '{}' value '{}'. Must be '1.0' or '1.1'.", INTERNAL_HTTP_VERSION_HEADER, value)),
+    }
+}
+
+// Actix writes the response with the version of the request, it can't be changed per response
+fn check_http_version(requested: actix_web::http::Version, actual: actix_web::http::Version) -> Result<(), String> {
+    if requested == actual {
+        return Ok(());
+    }
+    Err(format!(
+        "Can't answer a {:?} request with {:?}, the response always uses the version of the request. Send a {:?} request instead.",
+        actual, requested, requested
+    ))
+}
+
+fn decode_response_body_base64(encoded: &str) -> Result<Vec<u8>, String> {
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| format!("Invalid '{}' value: {}.", INTERNAL_RESPONSE_BODY_BASE64_HEADER, e))
+}
+
+fn parse_content_type(value: &str) -> Option<actix_web::mime::Mime> {
+    value.trim().parse::<actix_web::mime::Mime>().ok()
+}
+
+// The certificate is served through a ReloadableCertificate, so watch_tls_files can swap it
+fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<(rustls::ServerConfig, std::sync::Arc<ReloadableCertificate>), String> {
+    let certificate = std::sync::Arc::new(ReloadableCertificate(std::sync::RwLock::new(std::sync::Arc::new(
+        load_certified_key(cert_path, key_path)?,
+    ))));
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca_path {
+        Some(client_ca_path) => builder.with_client_cert_verifier(load_client_verifier(client_ca_path)?),
+        None => builder.with_no_client_auth(),
+    };
+    Ok((builder.with_cert_resolver(certificate.clone()), certificate))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<rustls::sign::CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Could not read TLS certificate '{}': {}.", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("TLS certificate '{}' does not contain any certificate.", cert_path));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Could not read TLS private key '{}': {}.", key_path, e))?;
+
+    rustls::sign::CertifiedKey::from_der(certs, key, &rustls::crypto::ring::default_provider())
+        .map_err(|e| format!("Invalid TLS certificate/key pair: {}.", e))
+}
+
+// The served certificate and key, read for every handshake so a reload applies to new connections only
+#[derive(Debug)]
+struct ReloadableCertificate(std::sync::RwLock<std::sync::Arc<rustls::sign::CertifiedKey>>);
+
+impl ReloadableCertificate {
+    fn current(&self) -> std::sync::Arc<rustls::sign::CertifiedKey> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Returns whether the certificate chain differs from the served one
+    fn replace(&self, certified_key: rustls::sign::CertifiedKey) -> bool {
+        let mut current = self.0.write().unwrap_or_else(|e| e.into_inner());
+        let changed = current.cert != certified_key.cert;
+        *current = std::sync::Arc::new(certified_key);
+        changed
+    }
+}
+
+impl rustls::server::ResolvesServerCert for ReloadableCertificate {
+    fn resolve(&self, _client_hello: rustls::server::ClientHello<'_>) -> Option<std::sync::Arc<rustls::sign::CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+// Reloads the certificate and key after their files change, keeping the served ones when the new
+// files can't be loaded. The directories are watched rather than the files, since rotations often
+// replace the files (or a symlink to them) instead of writing to them. Stops when the watcher is dropped.
+fn watch_tls_files(
+    cert_path: &str,
+    key_path: &str,
+    certificate: std::sync::Arc<ReloadableCertificate>,
+) -> notify::Result<notify::RecommendedWatcher> {
+    use notify::Watcher;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading the files ourselves only produces access events
+        if event.is_ok_and(|event| !event.kind.is_access()) {
+            let _ = sender.send(());
+        }
+    })?;
+    let mut directories = HashSet::new();
+    for path in [cert_path, key_path] {
+        let directory = match std::path::Path::new(path).parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        };
+        if directories.insert(directory.clone()) {
+            watcher.watch(&directory, notify::RecursiveMode::NonRecursive)?;
+        }
+    }
+
+    let (cert_path, key_path) = (cert_path.to_string(), key_path.to_string());
+    std::thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            // A rotation writes both files, and other files of the directories trigger events too
+            while receiver.recv_timeout(TLS_RELOAD_SETTLE_TIME).is_ok() {}
+            match load_certified_key(&cert_path, &key_path) {
+                Ok(certified_key) => {
+                    if certificate.replace(certified_key) {
+                        println!("🔐 Reloaded the TLS certificate from '{}', new connections use it", cert_path);
+                    }
+                }
+                Err(e) => eprintln!("Warning: Could not reload the TLS certificate ({}), keeping the previous one.", e),
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+fn validate_cache_control(cache_control: &str) -> Result<String, String> {
+    let cache_control = cache_control.trim();
+    if cache_control.is_empty() {
+        return Err(format!("Invalid '{}' value. Must not be empty.", INTERNAL_CACHE_CONTROL_HEADER));
+    }
+
+    Ok(cache_control.to_string())
+}
+
+fn validate_content_length(content_length: &str) -> Result<u64, String> {
+    content_length.trim().parse::<u64>().map_err(|_| {
+        format!(
+            "Invalid '{}' value '{}'. Must be a number of bytes.",
+            INTERNAL_CONTENT_LENGTH_HEADER, content_length
+        )
+    })
+}
+
+// Accepts the client certificates signed by one of the CA certificates in the file
+fn load_client_verifier(client_ca_path: &str) -> Result<std::sync::Arc<dyn rustls::server::danger::ClientCertVerifier>, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(client_ca_path)
+        .map_err(|e| format!("Could not read TLS client CA '{}': {}.", client_ca_path, e))?
+    {
+        let cert = cert.map_err(|e| format!("Could not read TLS client CA '{}': {}.", client_ca_path, e))?;
+        roots.add(cert).map_err(|e| format!("Invalid TLS client CA '{}': {}.", client_ca_path, e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("TLS client CA '{}' does not contain any certificate.", client_ca_path));
+    }
+
+    rustls::server::WebPkiClientVerifier::builder(std::sync::Arc::new(roots))
+        .build()
+        .map_err(|e| format!("Invalid TLS client CA '{}': {}.", client_ca_path, e))
+}
+
+// Common name of the certificate subject that a mutual TLS client presented, stored with the
+// connection so every request on it can echo it
+#[derive(Debug, Clone)]
+struct ClientCertSubject(String);
+
+fn store_client_cert_subject(connection: &dyn std::any::Any, data: &mut actix_web::dev::Extensions) {
+    let Some(tls) = connection.downcast_ref::<actix_tls::accept::rustls_0_23::TlsStream<actix_web::rt::net::TcpStream>>() else {
+        return;
+    };
+    let subject = tls
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| certificate_subject_cn(cert));
+    if let Some(subject) = subject {
+        data.insert(ClientCertSubject(subject));
+    }
+}
+
+// Splits the first DER element of `input` into its tag, its content and the bytes after it
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&length, rest) = rest.split_first()?;
+    let (length, rest) = if length < 0x80 {
+        (usize::from(length), rest)
+    } else {
+        let octets = usize::from(length & 0x7f);
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let (length, rest) = rest.split_at(octets);
+        (length.iter().fold(0, |length, &b| length << 8 | usize::from(b)), rest)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let (content, rest) = rest.split_at(length);
+    Some((tag, content, rest))
+}
+
+// Walks the X.509 structure down to the subject name and returns its commonName (2.5.4.3)
+fn certificate_subject_cn(der: &[u8]) -> Option<String> {
+    const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (_, certificate, _) = der_element(der)?;
+    let (_, mut tbs_certificate, _) = der_element(certificate)?;
+    // The version is optional, then come the serial number, signature algorithm, issuer and validity
+    if der_element(tbs_certificate)?.0 == 0xa0 {
+        tbs_certificate = der_element(tbs_certificate)?.2;
+    }
+    for _ in 0..4 {
+        tbs_certificate = der_element(tbs_certificate)?.2;
+    }
+
+    let (_, mut subject, _) = der_element(tbs_certificate)?;
+    while !subject.is_empty() {
+        let (_, mut attributes, rest) = der_element(subject)?;
+        subject = rest;
+        while !attributes.is_empty() {
+            let (_, attribute, rest) = der_element(attributes)?;
+            attributes = rest;
+            let (_, oid, value) = der_element(attribute)?;
+            if oid == COMMON_NAME_OID {
+                return Some(String::from_utf8_lossy(der_element(value)?.1).into_owned());
+            }
+        }
+    }
+    None
+}
+
+// Retry-After is either a number of seconds or an HTTP-date
+fn validate_retry_after(retry_after: &str) -> Result<String, String> {
+    let retry_after = retry_after.trim();
+    let is_seconds = !retry_after.is_empty() && retry_after.bytes().all(|c| c.is_ascii_digit());
+    let is_date = actix_web::http::header::HttpDate::from_str(retry_after).is_ok();
+
+    if !is_seconds && !is_date {
+        return Err(format!(
+            "Invalid '{}' value '{}'. Must be a number of seconds or an HTTP-date.",
+            INTERNAL_RETRY_AFTER_HEADER, retry_after
+        ));
+    }
+
+    Ok(retry_after.to_string())
+}
+
+// A redirect target must be an absolute path or an absolute http(s) URL
+fn validate_location(location: &str) -> Result<String, String> {
+    let location = location.trim();
+    let is_path = location.starts_with('/') && !location.starts_with("//");
+    let is_url = actix_web::http::Uri::from_str(location).is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.authority().is_some()
+    });
+
+    if !is_path && !is_url {
+        return Err(format!(
+            "Invalid '{}' value '{}'. Must be an absolute path or an http(s) URL.",
+            INTERNAL_LOCATION_HEADER, location
+        ));
+    }
+
+    Ok(location.to_string())
+}
+
+fn validate_delay_ms(delay_ms: u64, max_delay_ms: u64) -> Result<u64, String> {
+    if delay_ms > max_delay_ms {
+        return Err(format!(
+            "Invalid '{}' value {}. Must be at most {} milliseconds.",
+            INTERNAL_DELAY_MS_HEADER, delay_ms, max_delay_ms
+        ));
+    }
+
+    Ok(delay_ms)
+}
+
+fn validate_delay_jitter_ms(jitter_ms: u64, max_delay_ms: u64) -> Result<u64, String> {
+    if jitter_ms > max_delay_ms {
+        return Err(format!(
+            "Invalid '{}' value {}. Must be at most {} milliseconds.",
+            INTERNAL_DELAY_JITTER_MS_HEADER, jitter_ms, max_delay_ms
+        ));
+    }
+
+    Ok(jitter_ms)
+}
+
+// Uniformly distributed between `delay_ms - jitter_ms` and `delay_ms + jitter_ms`, then clamped to
+// 0..=max_delay_ms so the jitter never exceeds the delay cap
+fn jittered_delay_ms(delay_ms: u64, jitter_ms: u64, max_delay_ms: u64) -> u64 {
+    let jitter_ms = i64::try_from(jitter_ms).unwrap_or(i64::MAX);
+    let offset = if jitter_ms > 0 { rand::rng().random_range(-jitter_ms..=jitter_ms) } else { 0 };
+    delay_ms.saturating_add_signed(offset).min(max_delay_ms)
+}
+
+// `total;dur=12.3, delay;dur=10` in milliseconds, the delay metric only when a delay was injected
+fn server_timing(total: Duration, delay_ms: Option<u64>) -> String {
+    let mut metrics = format!("total;dur={:.1}", total.as_secs_f64() * 1000.0);
+    if let Some(delay_ms) = delay_ms {
+        let _ = write!(metrics, ", delay;dur={}", delay_ms);
+    }
+    metrics
+}
+
+// The whole body must drip within the delay cap
+fn validate_drip_ms(drip_ms: u64, body_len: usize, max_delay_ms: u64) -> Result<u64, String> {
+    let chunks = body_len.div_ceil(drip_chunk_size(body_len)) as u64;
+    let total_ms = drip_ms.saturating_mul(chunks.saturating_sub(1));
+    if total_ms > max_delay_ms {
+        return Err(format!(
+            "Invalid '{}' value {}. Sending the {} chunk(s) would take {} milliseconds, more than {}.",
+            INTERNAL_DRIP_MS_HEADER, drip_ms, chunks, total_ms, max_delay_ms
+        ));
+    }
+
+    Ok(drip_ms)
+}
+
+fn validate_pad_bytes(length: usize, max_length: usize) -> Result<usize, String> {
+    if length > max_length {
+        return Err(format!(
+            "Invalid '{}' value {}. Must be at most {} bytes.",
+            INTERNAL_PAD_BYTES_HEADER, length, max_length
+        ));
+    }
+
+    Ok(length)
+}
+
+// Deterministic, so clients can check every byte they downloaded
+fn pad_body(length: usize) -> web::Bytes {
+    PAD_PATTERN.iter().copied().cycle().take(length).collect::<Vec<u8>>().into()
+}
+
+fn validate_repeat_count(count: usize, body_len: usize, max_count: usize, max_size: usize) -> Result<usize, String> {
+    if count > max_count {
+        return Err(format!(
+            "Invalid '{}' value {}. Must be at most {}.",
+            INTERNAL_REPEAT_BODY_HEADER, count, max_count
+        ));
+    }
+
+    if count.saturating_mul(body_len) > max_size {
+        return Err(format!(
+            "Invalid '{}' value {}. The repeated body would exceed {} bytes.",
+            INTERNAL_REPEAT_BODY_HEADER, count, max_size
+        ));
+    }
+
+    Ok(count)
+}
+
+fn validate_hostname(hostname: &str, prefer_ipv6: bool) -> Result<IpAddr, String> {
+    if let Ok(ip) = IpAddr::from_str(hostname) {
+        return Ok(ip);
+    }
+
+    // Not a literal IP address, try resolving it as a DNS name
+    let addresses: Vec<IpAddr> = (hostname, 0)
+        .to_socket_addrs()
+        .map_err(|e| format!("Invalid hostname '{}'. Could not resolve it: {}.", hostname, e))?
+        .map(|addr| addr.ip())
+        .collect();
+
+    addresses
+        .iter()
+        .find(|ip| ip.is_ipv6() == prefer_ipv6)
+        .or_else(|| addresses.first())
+        .copied()
+        .ok_or_else(|| format!("Invalid hostname '{}'. It did not resolve to any address.", hostname))
+}
+
+fn validate_path(path: &str) -> Result<String, String> {
+    if !path.starts_with('/') {
+        return Err(format!("Invalid path '{}'. Must start with '/'.", path));
+    }
+
+    Ok(path.to_string())
+}
+
+// Without the trailing slash, so "/" means no prefix
+fn validate_base_path(path: &str) -> Result<String, String> {
+    if path.is_empty() {
+        return Ok(String::new());
+    }
+
+    let path = validate_path(path)?;
+    if path.contains(['?', '#', '{', '}']) {
+        return Err(format!("Invalid base path '{}'. Must be a plain path without a query or placeholders.", path));
+    }
+
+    Ok(path.trim_end_matches('/').to_string())
+}
+
+fn validate_port(port_str: &str) -> Result<u16, String> {
+    let port: u16 = port_str.parse()
+        .map_err(|_| format!("Invalid port '{}'. Must be a number between 1 and 65535.", port_str))?;
+
+    if port == 0 {
+        return Err(format!("Invalid port '{}'. Port cannot be 0, must be between 1 and 65535.", port_str));
+    }
+
+    Ok(port)
+}
+
+// First port that can be bound without privileges, Linux makes it configurable
+#[cfg(unix)]
+fn unprivileged_port_start() -> u16 {
+    std::fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+        .ok()
+        .and_then(|start| start.trim().parse().ok())
+        .unwrap_or(1024)
+}
+
+// The effective uid is the second field of the `Uid:` line. Without /proc (macOS, BSDs) the
+// process is taken for unprivileged, which at worst prints the warning needlessly.
+#[cfg(unix)]
+fn is_root() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let uids = status.lines().find_map(|line| line.strip_prefix("Uid:"))?;
+            uids.split_whitespace().nth(1).map(|euid| euid == "0")
+        })
+        .unwrap_or(false)
+}
+
+// The bind error for a privileged port only says "permission denied", so explain it up front
+#[cfg(unix)]
+fn privileged_port_warning(port: u16, unprivileged_port_start: u16, is_root: bool) -> Option<String> {
+    if is_root || port >= unprivileged_port_start {
+        return None;
+    }
+
+    Some(format!(
+        "Port {} is below {} and usually needs root privileges, binding will likely fail with a permission error. \
+         Use a port of {} or above, run as root, or grant the binary CAP_NET_BIND_SERVICE.",
+        port, unprivileged_port_start, unprivileged_port_start
+    ))
+}
+
+// Accepts a plain number of bytes or a number with a KB, MB or GB suffix (multiples of 1024)
+fn validate_byte_size(size_str: &str) -> Result<usize, String> {
+    let upper = size_str.trim().to_uppercase();
+    let (digits, multiplier) = match upper.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => match upper[index..].trim() {
+            "B" => (&upper[..index], 1),
+            "K" | "KB" => (&upper[..index], 1024),
+            "M" | "MB" => (&upper[..index], 1024 * 1024),
+            "G" | "GB" => (&upper[..index], 1024 * 1024 * 1024),
+            _ => return Err(format!("Invalid size '{}'. Use a number of bytes, optionally followed by KB, MB or GB.", size_str)),
+        },
+        None => (upper.as_str(), 1),
+    };
+
+    let size = digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size '{}'. Use a number of bytes, optionally followed by KB, MB or GB.", size_str))?;
+    if size == 0 {
+        return Err(format!("Invalid size '{}'. Must be greater than 0.", size_str));
+    }
+
+    Ok(size)
+}
+
+fn validate_auth(auth: &str) -> Result<String, String> {
+    match auth.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(auth.to_string()),
+        _ => Err(format!("Invalid credentials '{}'. Use the user:pass format.", auth)),
+    }
+}
+
+// The status code of a `/status/<code>` path under the base path, `None` for any other path
+fn path_status(req: &HttpRequest) -> Option<Result<StatusCode, String>> {
+    let path = format!("/{}", req.match_info().get("path")?);
+    path.strip_prefix(STATUS_PATH_PREFIX).map(validate_status)
+}
+
+fn validate_status(status_str: &str) -> Result<StatusCode, String> {
+    status_str
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .filter(|status| (100..600).contains(status))
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| format!("Invalid status code '{}'. Must be between 100 and 599.", status_str))
+}
+
+fn validate_content_type(value: &str) -> Result<HeaderValue, String> {
+    parse_content_type(value)
+        .and_then(|mime| HeaderValue::from_str(mime.as_ref()).ok())
+        .ok_or_else(|| format!("Invalid content type '{}'. Must be a MIME type like 'text/plain'.", value))
+}
+
+fn validate_workers(workers_str: &str) -> Result<usize, String> {
+    let workers: usize = workers_str.parse()
+        .map_err(|_| format!("Invalid worker count '{}'. Must be a positive number.", workers_str))?;
+
+    if workers == 0 {
+        return Err("Worker count cannot be 0. Must be at least 1.".to_string());
+    }
+
+    Ok(workers)
+}
+
+fn validate_ports<S: AsRef<str>>(port_strs: impl IntoIterator<Item = S>) -> Result<Vec<u16>, String> {
+    port_strs.into_iter().map(|s| validate_port(s.as_ref().trim())).collect()
+}
+
+// Binds an IPv6 listener that also accepts IPv4 connections, as IPv4-mapped addresses
+// Bound like actix binds its own listeners, for the servers built without HttpServer
+fn tcp_listener(addr: SocketAddr, backlog: u32, dual_stack: bool) -> std::io::Result<std::net::TcpListener> {
+    if dual_stack && addr.is_ipv6() {
+        return dual_stack_listener(addr, backlog);
+    }
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+fn dual_stack_listener(addr: SocketAddr, backlog: u32) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+// Resolves the bind addresses, each value is taken from the command line, then from the
+// environment, then from the settings file (which falls back to the built-in defaults).
+// The plain HTTP addresses come first, then the --https-port ones on the same host.
+fn resolve_bind_addresses(
+    matches: &clap::ArgMatches,
+    settings: &Settings,
+    settings_path: &str,
+) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>), String> {
+    let env_host = std::env::var(HOST_ENV_VAR).ok();
+    let (hostname_str, source) = match (matches.get_one::<String>("hostname"), &env_host) {
+        (Some(hostname), _) => (hostname.as_str(), "--hostname"),
+        (None, Some(hostname)) => (hostname.as_str(), HOST_ENV_VAR),
+        (None, None) => (settings.host.as_str(), settings_path),
+    };
+    let hostname = validate_hostname(hostname_str, matches.get_flag("ipv6"))
+        .map_err(|e| format!("{} (from {})", e, source))?;
+
+    let env_port = std::env::var(PORT_ENV_VAR).ok();
+    let ports = match (matches.get_many::<String>("port"), &env_port) {
+        (Some(port_strs), _) => validate_ports(port_strs).map_err(|e| format!("{} (from --port)", e))?,
+        (None, Some(port_str)) => validate_ports(port_str.split(',')).map_err(|e| format!("{} (from {})", e, PORT_ENV_VAR))?,
+        (None, None) => validate_ports([settings.port.to_string()]).map_err(|e| format!("{} (from {})", e, settings_path))?,
+    };
+    let https_ports = match matches.get_many::<String>("https-port") {
+        Some(port_strs) => validate_ports(port_strs).map_err(|e| format!("{} (from --https-port)", e))?,
+        None => match settings.https_port {
+            Some(port) => validate_ports([port.to_string()]).map_err(|e| format!("{} (from {})", e, settings_path))?,
+            None => Vec::new(),
+        },
+    };
+
+    let addresses = |ports: Vec<u16>| ports.into_iter().map(|port| SocketAddr::new(hostname, port)).collect();
+    Ok((addresses(ports), addresses(https_ports)))
+}
+
+fn build_cli() -> Command {
+    Command::new("Echo Server")
+        .version("1.0.1")
+        .about("A high-performance echo server that mirrors requests back as responses")
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("PATH")
+                .help("The settings file to load, TOML or JSON/YAML by its extension [default: Settings.toml]")
+        )
+        .arg(
+            Arg::new("hostname")
+                .short('n')
+                .long("hostname")
+                .value_name("HOSTNAME")
+                .help("The hostname/IP address to bind to [default: 127.0.0.1]")
+        )
+        .arg(
+            Arg::new("port")
+                .short('p')
+                .long("port")
+                .value_name("PORT")
+                .help("The port number to bind to, repeat the flag or use a comma-separated list to bind several [default: 8001]")
+                .value_delimiter(',')
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("https-port")
+                .long("https-port")
+                .value_name("PORT")
+                .help("Serve HTTPS on this port and plain HTTP on --port, instead of HTTPS on every port (needs --tls-cert and --tls-key)")
+                .value_delimiter(',')
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("uds")
+                .long("uds")
+                .value_name("PATH")
+                .help("Listen on a Unix domain socket instead of a TCP hostname and port (Unix only)")
+                .conflicts_with("https-port")
+        )
+        .arg(
+            Arg::new("ipv6")
+                .short('6')
+                .long("ipv6")
+                .help("Prefer an IPv6 address when the hostname resolves to several addresses")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("dual-stack")
+                .long("dual-stack")
+                .help("Accept IPv4 connections on IPv6 addresses too, e.g. a single '::' bind (disables IPV6_V6ONLY)")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("health-path")
+                .long("health-path")
+                .value_name("PATH")
+                .help("The path of the liveness endpoint [default: /health]")
+        )
+        .arg(
+            Arg::new("base-path")
+                .long("base-path")
+                .value_name("PATH")
+                .help("Serve every endpoint under this prefix, e.g. '/echo' behind a reverse proxy, and respond 404 outside of it")
+        )
+        .arg(
+            Arg::new("h2c")
+                .long("h2c")
+                .help("Accept HTTP/2 with prior knowledge (h2c) on plaintext connections, HTTPS always negotiates h2 via ALPN")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("no-continue")
+                .long("no-continue")
+                .help("Answer 'Expect: 100-continue' requests with 417 Expectation Failed instead of an interim 100 Continue")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("uds")
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .value_name("PATH")
+                .help("PEM certificate chain file, enables HTTPS together with --tls-key")
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .value_name("PATH")
+                .help("PEM private key file, enables HTTPS together with --tls-cert")
+        )
+        .arg(
+            Arg::new("tls-client-ca")
+                .long("tls-client-ca")
+                .value_name("PATH")
+                .help("PEM CA certificate file, requires clients to present a certificate signed by it (mutual TLS)")
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Respond with a JSON description of the request instead of the raw body")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("workers")
+                .short('w')
+                .long("workers")
+                .value_name("COUNT")
+                .help("The number of worker threads [default: number of CPU cores]")
+        )
+        .arg(
+            Arg::new("shutdown-timeout")
+                .long("shutdown-timeout")
+                .value_name("SECONDS")
+                .help("How long in-flight requests may take to complete on shutdown [default: 30]")
+                .value_parser(clap::value_parser!(u64))
+        )
+        .arg(
+            Arg::new("keep-alive")
+                .long("keep-alive")
+                .value_name("SECONDS")
+                .help("How long idle connections are kept open for further requests, 0 disables keep-alive [default: 5]")
+                .value_parser(clap::value_parser!(u64))
+        )
+        .arg(
+            Arg::new("report-interval")
+                .long("report-interval")
+                .value_name("SECONDS")
+                .help("Print the requests per second and the total number of requests every SECONDS seconds, even with --quiet")
+                .value_parser(clap::value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .value_name("COUNT")
+                .help("Most connections each worker serves at once, further ones wait until one closes [default: 25000]")
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+        )
+        .arg(
+            Arg::new("backlog")
+                .long("backlog")
+                .value_name("COUNT")
+                .help("Connections the kernel queues until they are accepted, for bursts of new connections [default: 1024]")
+                .value_parser(clap::value_parser!(u32).range(1..=i32::MAX as i64))
+        )
+        .arg(
+            Arg::new("startup-delay-ms")
+                .long("startup-delay-ms")
+                .value_name("MILLISECONDS")
+                .help("Wait this long before binding, to reproduce startup-order races with readiness checks")
+                .value_parser(clap::value_parser!(u64))
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .help("Validate the arguments, settings file, TLS files and rules, print the resulting configuration and exit")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("response-file")
+                .long("response-file")
+                .value_name("PATH")
+                .help("Always respond with the content of this file instead of the request body")
+        )
+        .arg(
+            Arg::new("template-file")
+                .long("template-file")
+                .value_name("PATH")
+                .help("Respond with this file rendered for every request, placeholders like {method}, {path} or {header.<name>} are substituted")
+                .conflicts_with("response-file")
+        )
+        .arg(
+            Arg::new("default-status")
+                .long("default-status")
+                .value_name("CODE")
+                .help("Status code of responses that don't override it with internal.status-code (default: 200)")
+        )
+        .arg(
+            Arg::new("default-content-type")
+                .long("default-content-type")
+                .value_name("MIME")
+                .help("Content-Type of echoed responses when neither the request nor internal.content-type sets one")
+        )
+        .arg(
+            Arg::new("max-body-size")
+                .long("max-body-size")
+                .value_name("SIZE")
+                .help("Largest accepted request body, in bytes or with a KB/MB/GB suffix (default: 10MB)")
+        )
+        .arg(
+            Arg::new("max-pad-bytes")
+                .long("max-pad-bytes")
+                .value_name("SIZE")
+                .help("Largest body internal.pad-bytes can generate, in bytes or with a KB/MB/GB suffix (default: 64MB)")
+        )
+        .arg(
+            Arg::new("auth")
+                .long("auth")
+                .value_name("USER:PASS")
+                .help("Require HTTP Basic authentication with these credentials on echoed requests (for testing only)")
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("PATH")
+                .help("Serve canned responses by method and path from this TOML or JSON file, other requests are echoed")
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("PATH")
+                .help("Append a JSON line per echoed request (timestamp, method, path, headers, body) to this file")
+        )
+        .arg(
+            Arg::new("allow-ip")
+                .long("allow-ip")
+                .value_name("CIDR")
+                .help("Only serve clients from this address or CIDR range, others get 403 Forbidden. Repeat the flag or use a comma-separated list")
+                .value_delimiter(',')
+                .action(clap::ArgAction::Append)
+                .value_parser(|s: &str| s.parse::<IpNet>().map(|_| s.trim().to_string()))
+        )
+        .arg(
+            Arg::new("trust-forwarded-for")
+                .long("trust-forwarded-for")
+                .help("Check the last X-Forwarded-For entry against --allow-ip instead of the peer address, only safe behind a proxy that sets it")
+                .action(clap::ArgAction::SetTrue)
+                .requires("allow-ip")
+        )
+        .arg(
+            Arg::new("shutdown-token")
+                .long("shutdown-token")
+                .value_name("TOKEN")
+                .help("Enable POST /__shutdown, which stops the server gracefully when given this token as 'Authorization: Bearer <TOKEN>'")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+        )
+        .arg(
+            Arg::new("rate-limit")
+                .long("rate-limit")
+                .value_name("RPS")
+                .help("Respond with 429 Too Many Requests above this many requests per second")
+                .value_parser(clap::value_parser!(u32).range(1..))
+        )
+        .arg(
+            Arg::new("rate-limit-by-ip")
+                .long("rate-limit-by-ip")
+                .help("Apply --rate-limit per client (X-Real-IP header or peer address) instead of globally")
+                .action(clap::ArgAction::SetTrue)
+                .requires("rate-limit")
+        )
+        .arg(
+            Arg::new("compress")
+                .long("compress")
+                .help("Compress responses according to the request's Accept-Encoding")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("cors")
+                .long("cors")
+                .help("Answer CORS preflight requests and add CORS headers, see the [cors] section of Settings.toml")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("websocket")
+                .long("websocket")
+                .help("Enable the WebSocket echo endpoint at /ws")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("echo-favicon")
+                .long("echo-favicon")
+                .help("Echo /favicon.ico requests instead of answering them with 204 No Content")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("reserved-headers-file")
+                .long("reserved-headers-file")
+                .value_name("PATH")
+                .help("File listing one reserved (never echoed) header per line, replacing the built-in list and reserved_headers")
+        )
+        .arg(
+            Arg::new("preserve-header-case")
+                .long("preserve-header-case")
+                .help("Write echoed header names in Title-Case like most clients send them, instead of lowercase (HTTP/1.x only)")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("server-timing")
+                .long("server-timing")
+                .help("Add a Server-Timing header with the processing time and the injected delay to echoed responses")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .help("Reject malformed internal headers with a 400 application/problem+json response instead of ignoring them")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("matched-rule-header")
+                .long("matched-rule-header")
+                .help("Name the [[rules]] or [[routes]] entry that decided the response, or 'echo', in the X-Matched-Rule header")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("self-check")
+                .long("self-check")
+                .help("After binding, send one request to the echo handler and exit with an error if it fails")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["once", "tls-client-ca"])
+        )
+        .arg(
+            Arg::new("once")
+                .long("once")
+                .help("Handle a single echoed request, print its details and exit")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Format of the verbose request/response logging: 'text' or 'json' [default: text]")
+                .value_parser(|s: &str| s.parse::<LogFormat>())
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .value_name("WHEN")
+                .help("Color the text verbose logging: 'auto' (when stdout is a terminal), 'always' or 'never' [default: auto]")
+                .value_parser(|s: &str| s.parse::<ColorMode>())
+        )
+        .arg(
+            Arg::new("no-color")
+                .long("no-color")
+                .help("Don't color the verbose logging, same as --color never")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("color")
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Append the verbose request/response logging to this file instead of stdout, implies --verbose")
+        )
+        .arg(
+            Arg::new("access-log-format")
+                .long("access-log-format")
+                .value_name("FORMAT")
+                .help("Format of the access log lines: 'clf' (Common Log Format) or 'combined' [default: actix format]")
+                .value_parser(|s: &str| s.parse::<AccessLogFormat>())
+        )
+        .arg(
+            Arg::new("no-access-log")
+                .long("no-access-log")
+                .help("Don't log a line per request, which saves the logging overhead in benchmarks")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("access-log-format")
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Enable verbose logging of requests and responses")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Suppress the startup banner, shutdown messages and the request details printed by --once, errors are still printed")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("verbose")
+        )
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("bench")
+                .about("Serve on a local port and load the server with concurrent keep-alive connections, then report throughput and latency")
+                .arg(
+                    Arg::new("requests")
+                        .short('n')
+                        .long("requests")
+                        .value_name("COUNT")
+                        .help(format!("The total number of requests [default: {}]", DEFAULT_BENCH_REQUESTS))
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .short('c')
+                        .long("concurrency")
+                        .value_name("COUNT")
+                        .help(format!("The number of connections sending requests at the same time [default: {}]", DEFAULT_BENCH_CONCURRENCY))
+                        .value_parser(clap::value_parser!(u64).range(1..=10_000))
+                )
+                .arg(
+                    Arg::new("body-size")
+                        .short('b')
+                        .long("body-size")
+                        .value_name("BYTES")
+                        .help(format!("The size of the body of every request [default: {}]", DEFAULT_BENCH_BODY_SIZE))
+                        .value_parser(clap::value_parser!(u64).range(..=DEFAULT_MAX_BODY_SIZE as u64))
+                )
+        )
+}
+
+/// Runs the grecho command line: reads the arguments, environment and settings file, then serves
+/// until SIGINT/SIGTERM
+pub async fn run() -> std::io::Result<()> {
+    // Parse command line arguments, values not provided by the user fall back to env vars and settings
+    let matches = build_cli().get_matches();
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        return run_bench(bench_matches).await;
+    }
+
+    // Load settings from the --config file, or from Settings.toml with fallback defaults
+    let settings_path = matches
+        .get_one::<String>("config")
+        .map(|s| s.as_str())
+        .unwrap_or(DEFAULT_SETTINGS_PATH);
+    let dry_run = matches.get_flag("dry-run");
+    let settings = match Settings::load(settings_path) {
+        Ok(settings) => settings,
+        // A dry run is there to catch a broken default settings file too, only a missing one is fine
+        Err(e) if matches.contains_id("config") || (dry_run && std::path::Path::new(settings_path).exists()) => {
+            eprintln!("Error: Could not load config file '{}' ({}).", settings_path, e);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Warning: Could not load {} ({}). Using default values.", settings_path, e);
+            Settings::default()
+        }
+    };
+
+    // Extract Unix domain socket path, which replaces the TCP hostname and ports
+    let uds_path = matches.get_one::<String>("uds");
+    if uds_path.is_some() && cfg!(not(unix)) {
+        eprintln!("Error: --uds is only supported on Unix platforms.");
+        std::process::exit(1);
+    }
+
+    let (bind_addresses, https_bind_addresses) = match uds_path {
+        Some(_) => (Vec::new(), Vec::new()),
+        None => match resolve_bind_addresses(&matches, &settings, settings_path) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    #[cfg(unix)]
+    for bind_address in bind_addresses.iter().chain(&https_bind_addresses) {
+        if let Some(warning) = privileged_port_warning(bind_address.port(), unprivileged_port_start(), is_root()) {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+
+    // Extract health path - use CLI arg if provided, otherwise use settings
+    let health_path_str = matches.get_one::<String>("health-path")
+        .map(|s| s.as_str())
+        .or(settings.health_path.as_deref())
+        .unwrap_or(DEFAULT_HEALTH_PATH);
+    let health_path = match validate_path(health_path_str) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Extract base path - use CLI arg if provided, otherwise use settings
+    let base_path_str = matches.get_one::<String>("base-path")
+        .map(|s| s.as_str())
+        .or(settings.base_path.as_deref())
+        .unwrap_or_default();
+    let base_path = match validate_base_path(base_path_str) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Extract TLS cert and key - use CLI args if provided, otherwise use settings
+    let tls_cert = matches.get_one::<String>("tls-cert").or(settings.tls_cert.as_ref());
+    let tls_key = matches.get_one::<String>("tls-key").or(settings.tls_key.as_ref());
+    if tls_cert.is_some() != tls_key.is_some() {
+        eprintln!("Error: Both --tls-cert and --tls-key must be provided to enable HTTPS.");
+        std::process::exit(1);
+    }
+    let tls = tls_cert.is_some();
+    if !https_bind_addresses.is_empty() && !tls {
+        eprintln!("Error: --https-port needs --tls-cert and --tls-key.");
+        std::process::exit(1);
+    }
+    let tls_client_ca = matches.get_one::<String>("tls-client-ca").or(settings.tls_client_ca.as_ref());
+    if tls_client_ca.is_some() && !tls {
+        eprintln!("Error: --tls-client-ca needs HTTPS, provide --tls-cert and --tls-key too.");
+        std::process::exit(1);
+    }
+    let h2c = matches.get_flag("h2c");
+    let no_continue = matches.get_flag("no-continue");
+    let dual_stack = matches.get_flag("dual-stack");
+    if tls && uds_path.is_some() {
+        eprintln!("Error: HTTPS is not supported over a Unix domain socket.");
+        std::process::exit(1);
+    }
+
+    // Extract default response format
+    let default_format = if matches.get_flag("json") { ResponseFormat::Json } else { ResponseFormat::Echo };
+
+    // Extract worker count - use CLI arg if provided, otherwise use settings, otherwise one per CPU core
+    let workers = match matches.get_one::<String>("workers") {
+        Some(workers_str) => validate_workers(workers_str),
+        None => match settings.workers {
+            Some(workers) => validate_workers(&workers.to_string()),
+            None => Ok(num_cpus::get()),
+        },
+    };
+    let workers = match workers {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Extract graceful shutdown timeout
+    let shutdown_timeout = matches
+        .get_one::<u64>("shutdown-timeout")
+        .copied()
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS);
+
+    // Extract keep-alive timeout
+    let keep_alive = matches
+        .get_one::<u64>("keep-alive")
+        .copied()
+        .unwrap_or(DEFAULT_KEEP_ALIVE_SECS);
+
+    // Extract throughput report interval
+    let report_interval = matches.get_one::<u64>("report-interval").copied();
+
+    // Extract per-worker connection limit
+    let max_connections = matches.get_one::<usize>("max-connections").copied();
+
+    // Extract listen backlog size
+    let backlog = matches.get_one::<u32>("backlog").copied().unwrap_or(DEFAULT_LISTEN_BACKLOG);
+
+    // Extract fixed response file
+    let response_file = matches.get_one::<String>("response-file");
+
+    // Extract response template file
+    let template_file = matches.get_one::<String>("template-file");
+
+    // Extract default status code - use CLI arg if provided, otherwise use settings, otherwise 200
+    let default_status = match matches.get_one::<String>("default-status") {
+        Some(status_str) => validate_status(status_str),
+        None => match settings.default_status {
+            Some(status) => validate_status(&status.to_string()),
+            None => Ok(StatusCode::OK),
+        },
+    };
+    let default_status = match default_status {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Extract default content type - use CLI arg if provided, otherwise use settings
+    let default_content_type = matches
+        .get_one::<String>("default-content-type")
+        .cloned()
+        .or_else(|| settings.default_content_type.clone());
+    if let Some(Err(e)) = default_content_type.as_deref().map(validate_content_type) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+
+    // Extract request body size limit
+    let max_body_size = match matches.get_one::<String>("max-body-size").map(|size| validate_byte_size(size)) {
+        Some(Ok(size)) => size,
+        Some(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        None => DEFAULT_MAX_BODY_SIZE,
+    };
+
+    // Extract padding size limit
+    let max_pad_bytes = match matches.get_one::<String>("max-pad-bytes").map(|size| validate_byte_size(size)) {
+        Some(Ok(size)) => size,
+        Some(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        None => DEFAULT_MAX_PAD_BYTES,
+    };
+
+    // Extract path routes from settings
+    if let Err(e) = Routes::new(&settings.routes) {
+        eprintln!("Error: Invalid route in {}: {}", settings_path, e);
+        std::process::exit(1);
+    }
+
+    // Extract path regex rules from settings
+    if let Err(e) = Rules::new(&settings.rules) {
+        eprintln!("Error: Invalid rule in {}: {}", settings_path, e);
+        std::process::exit(1);
+    }
+
+    // Extract canned responses and request recording file
+    let replay_path = matches.get_one::<String>("replay");
+    let record_path = matches.get_one::<String>("record");
+
+    // Extract Basic auth credentials
+    let auth = match matches.get_one::<String>("auth").map(|auth| validate_auth(auth)) {
+        Some(Ok(credentials)) => Some(credentials),
+        Some(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    // Extract source address allowlist
+    let allow_ips: Vec<String> = matches.get_many::<String>("allow-ip").unwrap_or_default().cloned().collect();
+    let trust_forwarded_for = matches.get_flag("trust-forwarded-for");
+
+    // Extract shutdown endpoint token
+    let shutdown_token = matches.get_one::<String>("shutdown-token");
+
+    // Extract rate limit
+    let rate_limit = matches.get_one::<u32>("rate-limit").copied();
+    let rate_limit_by_ip = matches.get_flag("rate-limit-by-ip");
+
+    // Extract compression flag
+    let compress = matches.get_flag("compress");
+
+    // Extract CORS settings - enabled by the CLI flag, configured by a [cors] section in settings
+    let cors_settings = CorsSettings::resolve(matches.get_flag("cors"), settings.cors.as_ref());
+
+    // Extract WebSocket flag
+    let websocket = matches.get_flag("websocket");
+
+    // Extract favicon flag
+    let echo_favicon = matches.get_flag("echo-favicon");
+
+    // Extract strict mode
+    let strict = matches.get_flag("strict");
+
+    // Extract matched rule header flag
+    let matched_rule_header = matches.get_flag("matched-rule-header");
+
+    // Extract single request mode, which logs the request it handles
+    let once = matches.get_flag("once");
+
+    // Extract verbose and quiet flags and logging format
+    let log_file = matches.get_one::<String>("log-file");
+    let quiet = matches.get_flag("quiet");
+    // --once prints the request unless --quiet, --log-file still gets it since it isn't stdout
+    let verbose = matches.get_flag("verbose") || (once && !quiet) || log_file.is_some();
+    let log_format = matches.get_one::<LogFormat>("log-format").copied().unwrap_or(LogFormat::Text);
+    let color = match matches.get_flag("no-color") {
+        true => ColorMode::Never,
+        false => matches.get_one::<ColorMode>("color").copied().unwrap_or_default(),
+    };
+    let access_log = !matches.get_flag("no-access-log");
+    let access_log_format = matches.get_one::<AccessLogFormat>("access-log-format").copied();
+
+    // Extract header casing flag
+    let preserve_header_case = matches.get_flag("preserve-header-case");
+
+    // Extract Server-Timing flag
+    let server_timing = matches.get_flag("server-timing");
+
+    // Extract reserved headers file, which replaces the built-in and Settings.toml reserved headers
+    let reserved_headers_file = match matches.get_one::<String>("reserved-headers-file") {
+        Some(path) => match load_reserved_headers_file(path) {
+            Ok(headers) => Some(headers),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut reserved_headers: Vec<String> = ReservedHeaders::from_settings(&settings, reserved_headers_file.as_deref())
+        .0
+        .into_iter()
+        .collect();
+    reserved_headers.sort();
+
+    let config = ServerConfig {
+        settings_path: Some(settings_path.to_string()),
+        bind_addresses,
+        https_bind_addresses,
+        uds: uds_path.cloned(),
+        dual_stack,
+        tls_cert: tls_cert.cloned(),
+        tls_key: tls_key.cloned(),
+        tls_client_ca: tls_client_ca.cloned(),
+        h2c,
+        no_continue,
+        workers,
+        shutdown_timeout_secs: shutdown_timeout,
+        keep_alive_secs: keep_alive,
+        max_connections,
+        backlog: Some(backlog),
+        health_path: health_path.clone(),
+        base_path: base_path.clone(),
+        reserved_headers,
+        preserve_header_case,
+        server_timing,
+        default_format,
+        default_status: default_status.as_u16(),
+        default_content_type: default_content_type.clone(),
+        max_body_size,
+        max_delay_ms: MAX_DELAY_MS,
+        max_repeat_body_count: MAX_REPEAT_BODY_COUNT,
+        max_repeated_body_size: MAX_REPEATED_BODY_SIZE,
+        max_pad_bytes,
+        response_file: response_file.cloned(),
+        template_file: template_file.cloned(),
+        replay: replay_path.cloned(),
+        routes: settings.routes.clone(),
+        rules: settings.rules.clone(),
+        record: record_path.cloned(),
+        auth: auth.clone(),
+        allow_ips: allow_ips.clone(),
+        trust_forwarded_for,
+        shutdown_token: shutdown_token.cloned(),
+        rate_limit,
+        rate_limit_by_ip,
+        compress,
+        cors: cors_settings.clone(),
+        websocket,
+        echo_favicon,
+        strict,
+        matched_rule_header,
+        once,
+        verbose,
+        log_format,
+        color,
+        log_file: log_file.cloned(),
+        access_log,
+        access_log_format,
+    };
+
+    if dry_run {
+        if let Err(e) = check_config(&config) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        if !quiet {
+            println!("✅ Configuration is valid (--dry-run), nothing was bound");
+            println!("{}", serde_json::to_string_pretty(&config).map_err(std::io::Error::other)?);
+        }
+        return Ok(());
+    }
+
+    // Delay binding, so readiness checks see the server come up late
+    if let Some(&startup_delay_ms) = matches.get_one::<u64>("startup-delay-ms") {
+        if !quiet {
+            println!("⏳ Waiting {}ms before binding (--startup-delay-ms)...", startup_delay_ms);
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(startup_delay_ms)).await;
+        if !quiet {
+            println!("⏳ Startup delay over, binding now");
+        }
+    }
+
+    let self_check_config = matches.get_flag("self-check").then(|| config.clone());
+
+    // Create and run the HTTP server
+    let server = match start_server(config).await {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if !quiet {
+        for url in server.urls() {
+            println!("🚀 Starting Echo Server on {}", url);
+        }
+        if let Some(path) = uds_path {
+            println!("🚀 Starting Echo Server on unix:{}", path);
+        }
+        if dual_stack && server.addrs().iter().any(SocketAddr::is_ipv6) {
+            println!("🌍 Dual-stack enabled - IPv6 addresses accept IPv4 connections too");
+        } else if dual_stack {
+            println!("⚠️  --dual-stack has no effect, none of the bind addresses is IPv6");
+        }
+        if tls {
+            println!("🔐 HTTPS enabled - HTTP/2 and HTTP/1.1 are negotiated via ALPN");
+            println!("♻️  The TLS certificate and key are reloaded when their files change");
+            if server.schemes.contains(&"http") {
+                println!("🔓 Plain HTTP is served alongside HTTPS on the --port address(es)");
+            }
+            if let Some(path) = tls_client_ca {
+                println!("🪪 Mutual TLS enabled - clients need a certificate signed by {}, its CN is echoed in '{}'", path, CLIENT_CERT_SUBJECT_HEADER);
+            }
+        }
+        if h2c && server.schemes.contains(&"http") {
+            println!("⚡ HTTP/2 prior knowledge (h2c) enabled alongside HTTP/1.1");
+        }
+        if no_continue {
+            println!("🙅 'Expect: 100-continue' requests get 417 Expectation Failed instead of 100 Continue");
+        }
+        println!("⚙️  Configuration loaded from {} (host: {}, port: {})", settings_path, settings.host, settings.port);
+        println!(
+            "⚙️  Host and port precedence: command line > {}/{} > {} > built-in defaults",
+            HOST_ENV_VAR, PORT_ENV_VAR, settings_path
+        );
+        println!("🧵 Running with {} worker(s)", workers);
+        if keep_alive == 0 {
+            println!("🔗 Keep-alive disabled - connections are closed after every response");
+        } else {
+            println!("🔗 Idle connections are kept alive for {}s", keep_alive);
+        }
+        if let Some(max_connections) = max_connections {
+            println!(
+                "🚧 At most {} connection(s) per worker, {} in total - further ones wait in the backlog",
+                max_connections,
+                max_connections * workers
+            );
+        }
+        println!("📥 Up to {} pending connection(s) are queued on every listening socket", backlog);
+        if !base_path.is_empty() {
+            println!("📁 Every endpoint is served under '{}', other paths get 404", base_path);
+        }
+        println!("💓 Health endpoint available at '{}{}'", base_path, health_path);
+        println!("📊 Request statistics available at '{}{}' (DELETE to reset)", base_path, STATS_PATH);
+        println!("📈 Prometheus metrics available at '{}{}'", base_path, METRICS_PATH);
+        println!("🧭 Effective configuration available at '{}{}'", base_path, CONFIG_PATH);
+        println!("📋 Headers that are relevant for the request only, like 'host' or 'user-agent' won't be echoed.");
+        println!("⚙️  Use '{}' header to override response status code", INTERNAL_STATUS_CODE_HEADER);
+        println!("🔢 Requests to '{}{}<code>' get that status code", base_path, STATUS_PATH_PREFIX);
+        println!("📝 Use '{}' header to override response body", INTERNAL_RESPONSE_BODY_HEADER);
+        println!("🧱 Use '{}' header to get a generated body of N bytes (at most {})", INTERNAL_PAD_BYTES_HEADER, max_pad_bytes);
+        println!("✂️  Use '{}' header to send only the first N bytes of the response body", INTERNAL_TRUNCATE_BODY_HEADER);
+        println!("📦 Use '{}' header to send a binary response body given in base64", INTERNAL_RESPONSE_BODY_BASE64_HEADER);
+        println!("⏱️  Use '{}' header to delay the response, and '{}' to randomize it", INTERNAL_DELAY_MS_HEADER, INTERNAL_DELAY_JITTER_MS_HEADER);
+        println!("💧 Use '{}' header to send the response body slowly, byte by byte", INTERNAL_DRIP_MS_HEADER);
+        println!("🏷️  Use '{}' header to override response Content-Type", INTERNAL_CONTENT_TYPE_HEADER);
+        println!("🔌 Use '{}: close' or '{}: true' headers to close or drop the connection", INTERNAL_CONNECTION_HEADER, INTERNAL_ABORT_HEADER);
+        println!("↪️  Use '{}' header with a 3xx status code to redirect", INTERNAL_LOCATION_HEADER);
+        println!("⏳ Use '{}' header with a 503 or 429 status code to set Retry-After", INTERNAL_RETRY_AFTER_HEADER);
+        println!("🗄️  Use '{}' header to set the response Cache-Control", INTERNAL_CACHE_CONTROL_HEADER);
+        println!("🧷 Use '{}: Name: Value' header to send a trailer after a chunked body (HTTP/1.1 only)", INTERNAL_TRAILER_HEADER);
+        println!("💥 Use '{}' header to send a Content-Length that doesn't match the body (HTTP/1.1 only)", INTERNAL_CONTENT_LENGTH_HEADER);
+        println!("📋 Use '{}' header to echo only the listed request headers", INTERNAL_ECHO_HEADERS_HEADER);
+        println!("🔁 Use '{}' header to repeat the echoed body", INTERNAL_REPEAT_BODY_HEADER);
+        println!("🧾 Use '{}: json' header to get a JSON description of the request", INTERNAL_FORMAT_HEADER);
+        println!("#️⃣  Use '{}: sha256' (or md5, crc32) header to get a checksum of the body instead of the body", INTERNAL_CHECKSUM_HEADER);
+        println!("🔖 Use '{}: 1.0|1.1' header to require the response HTTP version", INTERNAL_HTTP_VERSION_HEADER);
+        println!("🗜️  Use '{}: gzip' header to gzip the response body even if the client doesn't accept it", INTERNAL_FORCE_ENCODING_HEADER);
+        println!("🔬 Use '{}: true' header to get the whole request back as text, reserved headers included", INTERNAL_RAW_HEADER);
+        println!("🎲 Use '{}' header with a comma-separated list to pick a random status code", INTERNAL_RANDOM_STATUS_HEADER);
+        println!("🧩 Use '{}' header with placeholders like {{method}} or {{header.x-foo}} to build the response body", INTERNAL_TEMPLATE_HEADER);
+        println!("🍪 Use '{}' header to set response cookies", INTERNAL_SET_COOKIE_HEADER);
+        println!("🔀 Use '{}: old:new' header to copy a request header under a new name", INTERNAL_RENAME_HEADER_HEADER);
+        println!("❓ Use '{}: true' header to get the parsed query string back as a JSON object", INTERNAL_REFLECT_QUERY_HEADER);
+        println!("🍪 Use '{}: true' header to get the request cookies back as a JSON object", INTERNAL_REFLECT_COOKIES_HEADER);
+        println!("🕵️  Use '{}: true' header to get the connection's source address in '{}'", INTERNAL_REFLECT_PEER_HEADER, PEER_ADDR_HEADER);
+        println!("👯 Use '{}: name:count' header to send a response header several times", INTERNAL_DUPLICATE_HEADER_HEADER);
+        println!("➕ Use '{}: Name: Value' header to add a response header", INTERNAL_ADD_HEADER_HEADER);
+        println!("🌊 Use '{}: true' header to stream the body back as a chunked response", INTERNAL_STREAM_HEADER);
+        if let Some(path) = response_file {
+            println!("📄 Responding with the content of '{}'", path);
+        }
+        if let Some(path) = template_file {
+            println!("📄 Responding with '{}' rendered for every request", path);
+        }
+        if cors_settings.is_some() {
+            println!("🌐 CORS enabled - preflight requests are answered instead of echoed");
+        }
+        if websocket {
+            println!("🔌 WebSocket echo endpoint available at '{}{}'", base_path, WEBSOCKET_PATH);
+        }
+        if default_status != StatusCode::OK {
+            println!("🎯 Responding with status {} by default", default_status.as_u16());
+        }
+        if let Some(content_type) = &default_content_type {
+            println!("🏷️  Echoed responses without a Content-Type get '{}'", content_type);
+        }
+        println!("📦 Request bodies are limited to {} bytes", max_body_size);
+        if !settings.routes.is_empty() {
+            println!("🛣️  Serving {} route(s) from {}", settings.routes.len(), settings_path);
+        }
+        if !settings.rules.is_empty() {
+            println!("🧮 Evaluating {} regex rule(s) from {}", settings.rules.len(), settings_path);
+        }
+        if matched_rule_header && !(settings.routes.is_empty() && settings.rules.is_empty()) {
+            println!("🏷️  The rule or route that decided each response is named in '{}'", MATCHED_RULE_HEADER);
+        }
+        if let Some(path) = replay_path {
+            println!("🎞️  Replaying canned responses from {}", path);
+        }
+        if let Some(path) = record_path {
+            println!("📝 Recording requests to {}", path);
+        }
+        if auth.is_some() {
+            println!("🔑 Basic authentication required on echoed requests");
+        }
+        if !allow_ips.is_empty() {
+            let source = if trust_forwarded_for { "X-Forwarded-For" } else { "peer" };
+            println!("🛡️  Only serving clients from {} ({} address)", allow_ips.join(", "), source);
+        }
+        if shutdown_token.is_some() {
+            println!("🔌 POST '{}{}' with the --shutdown-token as a Bearer token stops the server", base_path, SHUTDOWN_PATH);
+        }
+        if let Some(rate_limit) = rate_limit {
+            let scope = if rate_limit_by_ip { "per client" } else { "globally" };
+            println!("🚦 Rate limited to {} requests per second {}", rate_limit, scope);
+        }
+        if compress {
+            println!("🗜️  Compression enabled - responses follow the request's Accept-Encoding");
+        }
+        if preserve_header_case {
+            println!("🔠 Echoed header names are written in Title-Case on HTTP/1.x");
+        }
+        if server_timing {
+            println!("⏲️  Echoed responses carry a Server-Timing header with the processing time and injected delay");
+        }
+        if strict {
+            println!("🧐 Strict mode enabled - malformed internal headers are rejected with 400");
+        }
+        if once {
+            println!("1️⃣  Single request mode - the server exits after the first echoed request");
+        } else if let Some(path) = log_file {
+            println!("🔍 Verbose mode enabled - requests and responses will be logged to {}", path);
+        } else if verbose {
+            println!("🔍 Verbose mode enabled - requests and responses will be logged");
+        }
+    }
+
+    // Catches what only fails at request time, the request goes through the first socket
+    if let Some(self_check_config) = self_check_config {
+        let addr = server.addrs().first().copied().map(self_check_address);
+        let tls = server.schemes.first() == Some(&"https");
+        let result = actix_web::rt::task::spawn_blocking(move || self_check(&self_check_config, addr, tls))
+            .await
+            .map_err(std::io::Error::other)?;
+        match result {
+            Ok(status) => {
+                if !quiet {
+                    println!("✅ Self-check passed: GET {}{} answered {}", base_path, SELF_CHECK_PATH, status);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: Self-check failed: {}", e);
+                server.stop(false).await;
+                if let Some(path) = uds_path {
+                    let _ = std::fs::remove_file(path);
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let reporter = report_interval
+        .map(|secs| actix_web::rt::spawn(report_throughput(server.stats.clone(), Duration::from_secs(secs))));
+
+    // Stop accepting new connections on SIGINT/SIGTERM and let in-flight requests finish
+    tokio::select! {
+        _ = wait_for_shutdown_signal() => {
+            if !quiet {
+                println!("🛑 Shutdown signal received, draining in-flight requests (up to {}s)...", shutdown_timeout);
+            }
+        }
+        _ = server.request_served() => {
+            if !quiet {
+                println!("🛑 Single request handled, shutting down...");
+            }
+        }
+        _ = server.shutdown_requested() => {
+            if !quiet {
+                println!("🛑 Shutdown requested at {}, draining in-flight requests (up to {}s)...", SHUTDOWN_PATH, shutdown_timeout);
+            }
+        }
+    }
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    server.stop(true).await;
+    let latency_summary = server.latency_summary();
+    server.wait().await?;
+
+    if let Some(path) = uds_path {
+        let _ = std::fs::remove_file(path);
+    }
+    if !quiet {
+        if let Some(summary) = latency_summary {
+            println!("⏱️  Latency over {}", summary);
+        }
+        println!("👋 Echo Server stopped");
+    }
+    Ok(())
+}
+
+/// A server started by [`start_server`], which keeps serving in the background until stopped
+#[derive(Debug)]
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    // `http` or `https` for each of the addrs
+    schemes: Vec<&'static str>,
+    base_path: String,
+    handle: actix_web::dev::ServerHandle,
+    single_request: web::Data<ServeOnce>,
+    shutdown_trigger: web::Data<ShutdownTrigger>,
+    stats: web::Data<Stats>,
+    metrics: web::Data<Metrics>,
+    // Reloads the TLS certificate as long as it's kept
+    _tls_watcher: Option<notify::RecommendedWatcher>,
+    task: tokio::task::JoinHandle<std::io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// TCP addresses the server listens on, with the actual ports when port 0 was asked for
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Base URL of the first TCP address, like `http://127.0.0.1:41234`, followed by the
+    /// [`ServerConfig::base_path`]
+    pub fn url(&self) -> Option<String> {
+        self.urls().into_iter().next()
+    }
+
+    /// Base URLs of every TCP address, the plain HTTP ones first when
+    /// [`ServerConfig::https_bind_addresses`] are served too
+    pub fn urls(&self) -> Vec<String> {
+        self.addrs
+            .iter()
+            .zip(&self.schemes)
+            .map(|(addr, scheme)| format!("{}://{}{}", scheme, addr, self.base_path))
+            .collect()
+    }
+
+    /// Resolves once the single request of [`ServerConfig::once`] has been served, never when
+    /// `once` is off
+    pub async fn request_served(&self) {
+        if self.single_request.enabled {
+            self.single_request.served.notified().await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Resolves once POST /__shutdown was called with the [`ServerConfig::shutdown_token`], never
+    /// without a token. The server keeps running until [`ServerHandle::stop`] is called.
+    pub async fn shutdown_requested(&self) {
+        if self.shutdown_trigger.token.is_some() {
+            self.shutdown_trigger.requested.notified().await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Requests handled by the echo handler so far, the `total` of /stats
+    pub fn requests_total(&self) -> u64 {
+        self.stats.total.load(Ordering::Relaxed)
+    }
+
+    /// Latency percentiles of the echoed requests so far, `None` before the first one
+    pub fn latency_summary(&self) -> Option<LatencySummary> {
+        self.metrics.latency_summary()
+    }
+
+    /// Stops accepting connections. With `graceful`, in-flight requests get up to
+    /// [`ServerConfig::shutdown_timeout_secs`] to complete.
+    pub async fn stop(&self, graceful: bool) {
+        self.handle.stop(graceful).await;
+    }
+
+    /// Waits until the server has stopped
+    pub async fn wait(self) -> std::io::Result<()> {
+        self.task.await.map_err(std::io::Error::other)?
+    }
+}
+
+// Everything start_server needs besides the sockets, once the configuration checks out
+struct PreparedServer {
+    health_path: String,
+    base_path: String,
+    workers: usize,
+    basic_auth: BasicAuth,
+    allowlist: IpAllowlist,
+    tls_config: Option<rustls::ServerConfig>,
+    tls_certificate: Option<std::sync::Arc<ReloadableCertificate>>,
+    handler_config: Config,
+}
+
+// Validation and file loading shared by start_server and check_config. The record and log files
+// are opened by start_server alone since that creates them.
+fn prepare_server(config: &ServerConfig) -> std::io::Result<PreparedServer> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    let health_path = validate_path(&config.health_path).map_err(invalid)?;
+    let base_path = validate_base_path(&config.base_path).map_err(invalid)?;
+    let default_status = validate_status(&config.default_status.to_string()).map_err(invalid)?;
+    let default_content_type = config.default_content_type.as_deref().map(validate_content_type).transpose().map_err(invalid)?;
+    let workers = validate_workers(&config.workers.to_string()).map_err(invalid)?;
+    if config.max_connections == Some(0) {
+        return Err(invalid("The connection limit must be at least 1.".to_string()));
+    }
+    if config.backlog == Some(0) {
+        return Err(invalid("The listen backlog must be at least 1.".to_string()));
+    }
+    if config.no_continue && config.uds.is_some() {
+        return Err(invalid("Refusing 'Expect: 100-continue' is not supported over a Unix domain socket.".to_string()));
+    }
+    let basic_auth = BasicAuth(config.auth.as_deref().map(validate_auth).transpose().map_err(invalid)?);
+    let allowlist = IpAllowlist::new(&config.allow_ips, config.trust_forwarded_for).map_err(invalid)?;
+    let routes = Routes::new(&config.routes).map_err(|e| invalid(format!("Invalid route: {}", e)))?;
+    let rules = Rules::new(&config.rules).map_err(|e| invalid(format!("Invalid rule: {}", e)))?;
+
+    if config.uds.is_some() && cfg!(not(unix)) {
+        return Err(invalid("Unix domain sockets are only supported on Unix platforms.".to_string()));
+    }
+    let (tls_config, tls_certificate) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            Some(load_tls_config(cert_path, key_path, config.tls_client_ca.as_deref()).map_err(invalid)?)
+        }
+        (None, None) if config.tls_client_ca.is_some() => {
+            return Err(invalid("A TLS client CA needs a TLS certificate and key.".to_string()));
+        }
+        (None, None) => None,
+        _ => return Err(invalid("Both a TLS certificate and key must be provided to enable HTTPS.".to_string())),
+    }
+    .unzip();
+    if tls_config.is_some() && config.uds.is_some() {
+        return Err(invalid("HTTPS is not supported over a Unix domain socket.".to_string()));
+    }
+    if tls_config.is_none() && !config.https_bind_addresses.is_empty() {
+        return Err(invalid("HTTPS addresses need a TLS certificate and key.".to_string()));
+    }
+
+    // Files are read once so requests don't hit the disk
+    let response_file = config.response_file.as_deref().map(ResponseFile::load).transpose().map_err(invalid)?;
+    if config.response_file.is_some() && config.template_file.is_some() {
+        return Err(invalid("A response file and a template file can't be used together.".to_string()));
+    }
+    let template_file = config.template_file.as_deref().map(Template::load).transpose().map_err(invalid)?;
+    let replay = config.replay.as_deref().map(Replay::load).transpose().map_err(invalid)?;
+
+    let handler_config = Config {
+        verbose: config.verbose,
+        log_format: config.log_format,
+        color: config.color.enabled(config.log_file.is_none()),
+        default_format: config.default_format,
+        default_status,
+        default_content_type,
+        reserved_headers: ReservedHeaders::new(&config.reserved_headers),
+        preserve_header_case: config.preserve_header_case,
+        server_timing: config.server_timing,
+        max_body_size: config.max_body_size,
+        max_delay_ms: config.max_delay_ms,
+        max_repeat_body_count: config.max_repeat_body_count,
+        max_repeated_body_size: config.max_repeated_body_size,
+        max_pad_bytes: config.max_pad_bytes,
+        strict: config.strict,
+        matched_rule_header: config.matched_rule_header && !(routes.is_empty() && rules.is_empty()),
+        response_file: response_file.unwrap_or_default(),
+        template_file,
+        replay: replay.unwrap_or_default(),
+        routes,
+        rules,
+    };
+
+    Ok(PreparedServer { health_path, base_path, workers, basic_auth, allowlist, tls_config, tls_certificate, handler_config })
+}
+
+/// Runs the checks of [`start_server`] without binding anything: validates `config`, parses the
+/// TLS files, compiles the routes and rules and loads the response and replay files.
+pub fn check_config(config: &ServerConfig) -> std::io::Result<()> {
+    prepare_server(config).map(|_| ())
+}
+
+/// Validates `config`, loads the files it refers to and binds every address, then serves in the
+/// background. Invalid settings are reported as [`std::io::ErrorKind::InvalidInput`].
+pub async fn start_server(config: ServerConfig) -> std::io::Result<ServerHandle> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+
+    let PreparedServer { health_path, base_path, workers, basic_auth, allowlist, tls_config, tls_certificate, handler_config } =
+        prepare_server(&config)?;
+    let recorder = config.record.as_deref().map(Recorder::open).transpose().map_err(invalid)?;
+    let verbose_log = config.log_file.as_deref().map(VerboseLog::open).transpose().map_err(invalid)?;
+
+    let compress = config.compress;
+    let cors_settings = config.cors.clone();
+    let websocket = config.websocket;
+    let echo_favicon = config.echo_favicon;
+    let access_log = config.access_log;
+    let access_log_format = config.access_log_format;
+    let handler_config = web::Data::new(handler_config);
+    let stats = web::Data::new(Stats::default());
+    let rate_limiter = web::Data::new(RateLimiter::new(config.rate_limit, config.rate_limit_by_ip));
+    let served_stats = stats.clone();
+    let metrics = web::Data::new(Metrics::default());
+    let served_metrics = metrics.clone();
+    let basic_auth = web::Data::new(basic_auth);
+    let allowlist = web::Data::new(allowlist);
+    let recorder = web::Data::new(recorder.unwrap_or_default());
+    let verbose_log = web::Data::new(verbose_log.unwrap_or_default());
+    let single_request = web::Data::new(ServeOnce::new(config.once));
+    let shutdown_trigger = web::Data::new(ShutdownTrigger {
+        token: config.shutdown_token.clone(),
+        ..ShutdownTrigger::default()
+    });
+    let served_config = web::Data::new(config.clone());
+
+    let app_single_request = single_request.clone();
+    let app_shutdown_trigger = shutdown_trigger.clone();
+    let app_base_path = base_path.clone();
+    let app = move || {
+        App::new()
+            .app_data(handler_config.clone())
+            .app_data(stats.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(metrics.clone())
+            .app_data(basic_auth.clone())
+            .app_data(allowlist.clone())
+            .app_data(recorder.clone())
+            .app_data(verbose_log.clone())
+            .app_data(app_single_request.clone())
+            .app_data(app_shutdown_trigger.clone())
+            .app_data(served_config.clone())
+            .app_data(handler_config.payload_config())
+            .wrap(Condition::new(compress, Compress::default()))
+            .wrap(Condition::new(
+                cors_settings.is_some(),
+                cors_settings.as_ref().map(CorsSettings::build).unwrap_or_default(),
+            ))
+            .wrap(from_fn(require_allowed_ip))
+            .wrap(Condition::new(access_log, access_logger(access_log_format, echo_favicon)))
+            // Browsers ask for it at the root whatever the base path
+            .configure(|cfg| {
+                if !echo_favicon {
+                    cfg.route(FAVICON_PATH, web::to(favicon_handler));
+                }
+            })
+            // An empty scope matches every path, so without a base path nothing changes
+            .service(
+                web::scope(&app_base_path)
+                    .route(&health_path, web::to(health_handler))
+                    // Guarded resources instead of routes, so other methods are echoed instead of getting a 405
+                    .service(
+                        web::resource(STATS_PATH)
+                            .guard(guard::Any(guard::Get()).or(guard::Delete()))
+                            .route(web::get().to(stats_handler))
+                            .route(web::delete().to(reset_stats_handler))
+                    )
+                    .configure(|cfg| {
+                        if websocket {
+                            cfg.service(web::resource(WEBSOCKET_PATH).guard(guard::Get()).to(websocket_handler));
+                        }
+                    })
+                    .service(web::resource(METRICS_PATH).guard(guard::Get()).to(metrics_handler))
+                    .service(web::resource(CONFIG_PATH).guard(guard::Get()).to(config_handler))
+                    .service(web::resource(SHUTDOWN_PATH).guard(guard::Post()).to(shutdown_handler))
+                    // The empty pattern is the bare base path, which gets the same middleware
+                    .service(
+                        web::resource(["", "/{path:.*}"])
+                            .wrap(from_fn(serve_once))
+                            .wrap(from_fn(require_basic_auth))
+                            .wrap(from_fn(record_metrics))
+                            .wrap(from_fn(title_case_headers))
+                            .route(web::route().guard(guard::fn_guard(is_stream_requested)).to(stream_handler))
+                            .to(echo_handler)
+                    )
+            )
+            .default_service(web::to(outside_base_path_handler))
+    };
+
+    let backlog = config.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG);
+    let (server, addrs, schemes) = if config.no_continue {
+        serve_without_continue(&config, app, tls_config.as_ref(), workers, backlog)?
+    } else {
+        serve(&config, app, tls_config.as_ref(), workers, backlog)?
+    };
+
+    // Rotated certificates are picked up without a restart
+    let tls_watcher = match (tls_certificate, &config.tls_cert, &config.tls_key) {
+        (Some(certificate), Some(cert_path), Some(key_path)) => match watch_tls_files(cert_path, key_path, certificate) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!("Warning: Could not watch the TLS files for changes ({}), they won't be reloaded.", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    Ok(ServerHandle {
+        addrs,
+        schemes,
+        base_path,
+        handle: server.handle(),
+        single_request,
+        shutdown_trigger,
+        stats: served_stats,
+        metrics: served_metrics,
+        _tls_watcher: tls_watcher,
+        task: tokio::spawn(server),
+    })
+}
+
+// Sends one request to the echo handler through the bound socket, like a client would, and
+// returns the response status. Blocking, so it must run off the async runtime.
+fn self_check(config: &ServerConfig, addr: Option<SocketAddr>, tls: bool) -> Result<String, String> {
+    let base_path = validate_base_path(&config.base_path)?;
+    let mut request = format!(
+        "GET {}{} HTTP/1.1\r\nHost: localhost\r\n{}: 200\r\n{}: {}\r\nConnection: close\r\n",
+        base_path,
+        SELF_CHECK_PATH,
+        INTERNAL_STATUS_CODE_HEADER,
+        INTERNAL_SELF_CHECK_HEADER,
+        self_check_token()
+    );
+    if let Some(credentials) = &config.auth {
+        let _ = write!(request, "Authorization: Basic {}\r\n", base64::engine::general_purpose::STANDARD.encode(credentials));
+    }
+    request.push_str("\r\n");
+
+    let response = match (addr, &config.uds) {
+        // actix reports a made up TCP address for a Unix domain socket
+        #[cfg(unix)]
+        (_, Some(path)) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)
+                .and_then(|stream| {
+                    stream.set_read_timeout(Some(SELF_CHECK_TIMEOUT))?;
+                    stream.set_write_timeout(Some(SELF_CHECK_TIMEOUT))?;
+                    Ok(stream)
+                })
+                .map_err(|e| format!("Could not connect to unix:{}: {}.", path, e))?;
+            exchange(stream, &request)
+        }
+        (Some(addr), None) => {
+            let stream = std::net::TcpStream::connect_timeout(&addr, SELF_CHECK_TIMEOUT)
+                .and_then(|stream| {
+                    stream.set_read_timeout(Some(SELF_CHECK_TIMEOUT))?;
+                    stream.set_write_timeout(Some(SELF_CHECK_TIMEOUT))?;
+                    Ok(stream)
+                })
+                .map_err(|e| format!("Could not connect to {}: {}.", addr, e))?;
+            match &config.tls_cert {
+                Some(cert_path) if tls => {
+                    let connection = self_check_tls_connection(cert_path)?;
+                    exchange(rustls::StreamOwned::new(connection, stream), &request)
+                }
+                _ => exchange(stream, &request),
+            }
+        }
+        _ => return Err("The server isn't listening on any address.".to_string()),
+    }
+    .map_err(|e| format!("The request failed: {}.", e))?;
+
+    // `HTTP/1.1 200 OK`, a server error means requests can't be served
+    let status_line = response.split(|&b| b == b'\r').next().map(String::from_utf8_lossy).unwrap_or_default();
+    let status = status_line.split_once(' ').map(|(_, status)| status.to_string()).unwrap_or_default();
+    match status.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+        Some(code) if code < 500 => Ok(status),
+        Some(_) => Err(format!("The server answered {}.", status)),
+        None => Err(format!("Invalid response '{}'.", status_line)),
+    }
+}
+
+// Random for every process, so only the self-check of this process can skip the statistics
+fn self_check_token() -> &'static str {
+    static TOKEN: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    TOKEN.get_or_init(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+fn is_self_check(headers: &HeaderMap) -> bool {
+    headers.get(INTERNAL_SELF_CHECK_HEADER).is_some_and(|value| value.as_bytes() == self_check_token().as_bytes())
+}
+
+// Writes the request and reads the response until the server closes the connection
+fn exchange(mut stream: impl std::io::Read + Write, request: &str) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    match stream.read_to_end(&mut response) {
+        // A TLS connection closed without close_notify still delivered the response
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => Ok(response),
+        result => result.map(|_| response),
+    }
+}
+
+fn self_check_tls_connection(cert_path: &str) -> Result<rustls::ClientConnection, String> {
+    let certificate = CertificateDer::from_pem_file(cert_path)
+        .map_err(|e| format!("Could not read TLS certificate '{}': {}.", cert_path, e))?;
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(std::sync::Arc::new(ServedCertificate { certificate, provider }))
+        .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from("localhost").map_err(|e| e.to_string())?;
+    rustls::ClientConnection::new(std::sync::Arc::new(config), server_name).map_err(|e| e.to_string())
+}
+
+// Trusts exactly the certificate grecho serves, so the self-check works with self-signed ones and
+// whatever name the certificate is for
+#[derive(Debug)]
+struct ServedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: std::sync::Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for ServedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("the server presented another certificate".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Where --self-check connects to reach a socket bound to a wildcard address
+fn self_check_address(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, addr.port())),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, addr.port())),
+        _ => addr,
+    }
+}
+
+// The addresses are returned with their scheme, in the order they were bound
+type Served = (actix_web::dev::Server, Vec<SocketAddr>, Vec<&'static str>);
+
+fn serve<F, I, S, B>(
+    config: &ServerConfig,
+    app: F,
+    tls_config: Option<&rustls::ServerConfig>,
+    workers: usize,
+    backlog: u32,
+) -> std::io::Result<Served>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: actix_service::IntoServiceFactory<S, actix_http::Request>,
+    S: actix_web::dev::ServiceFactory<actix_http::Request, Config = actix_web::dev::AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: std::fmt::Debug,
+    S::Response: Into<actix_http::Response<B>> + 'static,
+    <S::Service as actix_web::dev::Service<actix_http::Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    // Only applies to the sockets bound from here on, the dual-stack ones get it by hand
+    let mut server = HttpServer::new(app).on_connect(store_client_cert_subject).backlog(backlog);
+
+    // Without HTTPS addresses of their own, the plain ones serve HTTPS when it's configured
+    let plain_tls_config = if config.https_bind_addresses.is_empty() { tls_config } else { None };
+    let bind_addresses = config.bind_addresses.iter().map(|address| (address, plain_tls_config));
+    let https_bind_addresses = config.https_bind_addresses.iter().map(|address| (address, tls_config));
+    let mut schemes = Vec::new();
+    for (&bind_address, tls_config) in bind_addresses.chain(https_bind_addresses) {
+        // IPv6 sockets are set up by hand for dual-stack, since actix keeps the OS default for IPV6_V6ONLY
+        server = if config.dual_stack && bind_address.is_ipv6() {
+            let listener = dual_stack_listener(bind_address, backlog)?;
+            match tls_config {
+                Some(tls_config) => server.listen_rustls_0_23(listener, tls_config.clone())?,
+                None if config.h2c => server.listen_auto_h2c(listener)?,
+                None => server.listen(listener)?,
+            }
+        } else {
+            match tls_config {
+                Some(tls_config) => server.bind_rustls_0_23(bind_address, tls_config.clone())?,
+                None if config.h2c => server.bind_auto_h2c(bind_address)?,
+                None => server.bind(bind_address)?,
+            }
+        };
+        schemes.resize(server.addrs().len(), if tls_config.is_some() { "https" } else { "http" });
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.uds {
+        server = server.bind_uds(path)?;
+    }
+
+    let addrs = server.addrs();
+    let server = server
+        .workers(workers)
+        .shutdown_timeout(config.shutdown_timeout_secs)
+        .keep_alive(keep_alive(config.keep_alive_secs))
+        .max_connections(config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS))
+        .disable_signals()
+        .run();
+    Ok((server, addrs, schemes))
+}
+
+// actix-http writes the interim 100 Continue for every expectation its expect service accepts, and
+// HttpServer always installs the accepting one. So --no-continue builds the HTTP services by hand,
+// with an expect service refusing the expectation: the client gets a final response and no
+// interim one. The listeners and settings otherwise follow `serve`.
+fn serve_without_continue<F, I, S, B>(
+    config: &ServerConfig,
+    app: F,
+    tls_config: Option<&rustls::ServerConfig>,
+    workers: usize,
+    backlog: u32,
+) -> std::io::Result<Served>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: actix_service::IntoServiceFactory<S, actix_http::Request>,
+    S: actix_web::dev::ServiceFactory<actix_http::Request, Config = actix_web::dev::AppConfig> + 'static,
+    S::Future: 'static,
+    S::Error: Into<actix_http::Response<actix_web::body::BoxBody>> + 'static,
+    S::InitError: std::fmt::Debug,
+    S::Response: Into<actix_http::Response<B>> + 'static,
+    <S::Service as actix_web::dev::Service<actix_http::Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let keep_alive = keep_alive(config.keep_alive_secs);
+    let mut builder = actix_web::dev::Server::build()
+        .workers(workers)
+        .backlog(backlog)
+        .shutdown_timeout(config.shutdown_timeout_secs)
+        .max_concurrent_connections(config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS))
+        .disable_signals();
+
+    // The app doesn't build URLs, so the default connection config does
+    let factory = move || actix_service::map_config(app(), |_| actix_web::dev::AppConfig::default());
+
+    let plain_tls_config = if config.https_bind_addresses.is_empty() { tls_config } else { None };
+    let bind_addresses = config.bind_addresses.iter().map(|address| (address, plain_tls_config));
+    let https_bind_addresses = config.https_bind_addresses.iter().map(|address| (address, tls_config));
+    let (mut addrs, mut schemes) = (Vec::new(), Vec::new());
+    for (&bind_address, tls_config) in bind_addresses.chain(https_bind_addresses) {
+        let listener = tcp_listener(bind_address, backlog, config.dual_stack)?;
+        let addr = listener.local_addr()?;
+        let name = format!("grecho-{}", addr);
+        let factory = factory.clone();
+        builder = match tls_config.cloned() {
+            Some(tls_config) => builder.listen(name, listener, move || {
+                actix_http::HttpService::build()
+                    .keep_alive(keep_alive)
+                    .local_addr(addr)
+                    .expect(actix_web::dev::fn_service(refuse_continue))
+                    .on_connect_ext(|io: &_, data: &mut _| store_client_cert_subject(io, data))
+                    .finish(factory())
+                    .rustls_0_23(tls_config.clone())
+            })?,
+            None if config.h2c => builder.listen(name, listener, move || {
+                actix_http::HttpService::build()
+                    .keep_alive(keep_alive)
+                    .local_addr(addr)
+                    .expect(actix_web::dev::fn_service(refuse_continue))
+                    .finish(factory())
+                    .tcp_auto_h2c()
+            })?,
+            None => builder.listen(name, listener, move || {
+                actix_http::HttpService::build()
+                    .keep_alive(keep_alive)
+                    .local_addr(addr)
+                    .expect(actix_web::dev::fn_service(refuse_continue))
+                    .finish(factory())
+                    .tcp()
+            })?,
+        };
+        addrs.push(addr);
+        schemes.push(if tls_config.is_some() { "https" } else { "http" });
+    }
+
+    Ok((builder.run(), addrs, schemes))
+}
+
+// The expect service of --no-continue, the request isn't served and its body isn't read
+async fn refuse_continue(_: actix_http::Request) -> Result<actix_http::Request, actix_web::Error> {
+    Err(actix_web::error::ErrorExpectationFailed("Expect: 100-continue is refused by --no-continue"))
+}
+
+fn keep_alive(secs: u64) -> actix_web::http::KeepAlive {
+    match secs {
+        0 => actix_web::http::KeepAlive::Disabled,
+        secs => actix_web::http::KeepAlive::Timeout(Duration::from_secs(secs)),
+    }
+}
+
+// Prints the requests per second of every interval and the running total, for --report-interval
+async fn report_throughput(stats: web::Data<Stats>, interval: Duration) {
+    let mut ticker = actix_web::rt::time::interval(interval);
+    // The first tick completes right away
+    ticker.tick().await;
+    let mut previous = stats.total.load(Ordering::Relaxed);
+    loop {
+        ticker.tick().await;
+        let total = stats.total.load(Ordering::Relaxed);
+        // DELETE /stats starts the count over
+        let count = total.checked_sub(previous).unwrap_or(total);
+        previous = total;
+        println!("{}", throughput_report(count, total, interval));
+    }
+}
+
+fn throughput_report(count: u64, total: u64, interval: Duration) -> String {
+    format!(
+        "📈 {:.1} req/s over the last {}s ({} requests), {} in total",
+        count as f64 / interval.as_secs_f64(),
+        interval.as_secs(),
+        count,
+        total
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BenchOptions {
+    requests: u64,
+    concurrency: usize,
+    body_size: usize,
+}
+
+#[derive(Debug)]
+struct BenchReport {
+    options: BenchOptions,
+    elapsed: Duration,
+    failed: u64,
+    // Client side latencies, connecting included for the first request of every connection
+    latency: Option<LatencySummary>,
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "🏁 {} requests with {} byte bodies over {} connections in {:.3}s",
+            self.options.requests, self.options.body_size, self.options.concurrency, secs
+        )?;
+        write!(
+            f,
+            "🚀 {:.1} req/s, {:.1} MiB/s of request bodies",
+            self.options.requests as f64 / secs,
+            (self.options.requests * self.options.body_size as u64) as f64 / secs / (1024.0 * 1024.0)
+        )?;
+        if let Some(latency) = &self.latency {
+            write!(f, "\n⏱️  {}", latency)?;
+        }
+        if self.failed > 0 {
+            write!(f, "\n❌ {} request(s) failed", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+async fn run_bench(matches: &clap::ArgMatches) -> std::io::Result<()> {
+    let requests = matches.get_one::<u64>("requests").copied().unwrap_or(DEFAULT_BENCH_REQUESTS);
+    let concurrency = matches.get_one::<u64>("concurrency").copied().unwrap_or(DEFAULT_BENCH_CONCURRENCY);
+    let options = BenchOptions {
+        requests,
+        // More connections than requests would only sit idle
+        concurrency: concurrency.min(requests) as usize,
+        body_size: matches.get_one::<u64>("body-size").copied().unwrap_or(DEFAULT_BENCH_BODY_SIZE) as usize,
+    };
+
+    println!(
+        "🏋️ Benchmarking {} requests over {} connections with {} worker(s)...",
+        options.requests,
+        options.concurrency,
+        num_cpus::get()
+    );
+    let report = bench(options).await?;
+    println!("{}", report);
+    if report.failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Serves on a random loopback port, without the access log, and loads the server from blocking
+// client threads, one keep-alive connection each
+async fn bench(options: BenchOptions) -> std::io::Result<BenchReport> {
+    let config = ServerConfig {
+        workers: num_cpus::get(),
+        access_log: false,
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await?;
+    let addr = server.addrs()[0];
+
+    let started_at = Instant::now();
+    let load = actix_web::rt::task::spawn_blocking(move || bench_load(addr, options)).await;
+    let elapsed = started_at.elapsed();
+    server.stop(true).await;
+    server.wait().await?;
+
+    let (failed, latency) = load.map_err(std::io::Error::other)?;
+    Ok(BenchReport { options, elapsed, failed, latency })
+}
+
+fn bench_load(addr: SocketAddr, options: BenchOptions) -> (u64, Option<LatencySummary>) {
+    let mut request = format!(
+        "POST /bench HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+        addr, options.body_size
+    )
+    .into_bytes();
+    request.resize(request.len() + options.body_size, b'x');
+
+    let remaining = AtomicU64::new(options.requests);
+    let failed = AtomicU64::new(0);
+    let latencies = Mutex::new(LatencyReservoir::default());
+    std::thread::scope(|scope| {
+        for _ in 0..options.concurrency {
+            scope.spawn(|| bench_connection(addr, &request, &remaining, &failed, &latencies));
+        }
+    });
+
+    let latency = latencies.lock().ok().and_then(|latencies| latencies.summary());
+    (failed.load(Ordering::Relaxed), latency)
+}
+
+// Sends requests until none remain, reconnecting after a failed one
+fn bench_connection(
+    addr: SocketAddr,
+    request: &[u8],
+    remaining: &AtomicU64,
+    failed: &AtomicU64,
+    latencies: &Mutex<LatencyReservoir>,
+) {
+    let mut connection: Option<std::io::BufReader<std::net::TcpStream>> = None;
+    while remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok() {
+        let started_at = Instant::now();
+        let stream = match connection.take() {
+            Some(stream) => Ok(stream),
+            None => std::net::TcpStream::connect(addr)
+                .and_then(|stream| stream.set_nodelay(true).map(|_| std::io::BufReader::new(stream))),
+        };
+        match stream.and_then(|mut stream| bench_exchange(&mut stream, request).map(|status| (stream, status))) {
+            Ok((stream, status)) => {
+                if let Ok(mut latencies) = latencies.lock() {
+                    latencies.observe(started_at.elapsed());
+                }
+                if !(200..300).contains(&status) {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                }
+                connection = Some(stream);
+            }
+            Err(_) => {
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Writes the request and reads one keep-alive response, which needs a Content-Length
+fn bench_exchange(stream: &mut std::io::BufReader<std::net::TcpStream>, request: &[u8]) -> std::io::Result<u16> {
+    use std::io::{BufRead, Read};
+
+    stream.get_mut().write_all(request)?;
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid response");
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let status = line.split(' ').nth(1).and_then(|status| status.parse::<u16>().ok()).ok_or_else(invalid)?;
+
+    let mut content_length = None;
+    loop {
+        line.clear();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if line == "\r\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<u64>().ok();
+            }
+        }
+    }
+
+    let content_length = content_length.ok_or_else(invalid)?;
+    let read = std::io::copy(&mut stream.by_ref().take(content_length), &mut std::io::sink())?;
+    if read < content_length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(status)
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+            }
+            Err(e) => {
+                eprintln!("Warning: Could not listen for SIGTERM ({}). Only Ctrl-C will stop the server.", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Registers the routes and default app data the server starts with
+    fn test_config(cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::new(Config::default()))
+            .app_data(web::Data::new(Stats::default()))
+            .app_data(web::Data::new(RateLimiter::default()))
+            .app_data(web::Data::new(Metrics::default()))
+            .app_data(web::Data::new(BasicAuth::default()))
+            .app_data(web::Data::new(Recorder::default()))
+            .app_data(web::Data::new(VerboseLog::default()))
+            .app_data(web::Data::new(ServeOnce::default()))
+            .app_data(web::Data::new(ServerConfig::default()))
+            .app_data(Config::default().payload_config())
+            .route(DEFAULT_HEALTH_PATH, web::to(health_handler))
+            .service(
+                web::resource(STATS_PATH)
+                    .guard(guard::Any(guard::Get()).or(guard::Delete()))
+                    .route(web::get().to(stats_handler))
+                    .route(web::delete().to(reset_stats_handler)),
+            )
+            .service(web::resource(METRICS_PATH).guard(guard::Get()).to(metrics_handler))
+            .service(web::resource(CONFIG_PATH).guard(guard::Get()).to(config_handler))
+            .service(web::resource(WEBSOCKET_PATH).guard(guard::Get()).to(websocket_handler))
+            .service(
+                web::resource("/{path:.*}")
+                    .wrap(from_fn(serve_once))
+                    .wrap(from_fn(require_basic_auth))
+                    .wrap(from_fn(record_metrics))
+                    .wrap(from_fn(title_case_headers))
+                    .route(web::route().guard(guard::fn_guard(is_stream_requested)).to(stream_handler))
+                    .to(echo_handler),
+            );
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        assert!(validate_hostname("127.0.0.1", false).is_ok());
+        assert!(validate_hostname("0.0.0.0", false).is_ok());
+        assert!(validate_hostname("192.168.1.1", false).is_ok());
+        assert!(validate_hostname("::1", false).is_ok());
+        // `.invalid` never resolves (RFC 6761), whatever the resolver does with unknown names
+        assert!(validate_hostname("does-not-exist.invalid", false).is_err());
+        assert!(validate_hostname("999.999.999.999", false).is_err());
+    }
+
+    #[test]
+    fn test_validate_hostname_resolves_dns_names() {
+        assert!(validate_hostname("localhost", false).unwrap().is_loopback());
+        assert!(validate_hostname("does-not-exist.invalid", false).is_err());
+    }
+
+    #[test]
+    fn test_validate_base_path() {
+        assert_eq!(validate_base_path(""), Ok(String::new()));
+        assert_eq!(validate_base_path("/"), Ok(String::new()));
+        assert_eq!(validate_base_path("/echo/"), Ok("/echo".to_string()));
+        assert_eq!(validate_base_path("/api/echo"), Ok("/api/echo".to_string()));
+        assert!(validate_base_path("echo").is_err());
+        assert!(validate_base_path("/echo/{id}").is_err());
+    }
+
+    #[test]
+    fn test_validate_port() {
+        assert_eq!(validate_port("8001").unwrap(), 8001);
+        assert_eq!(validate_port("8080").unwrap(), 8080);
+        assert_eq!(validate_port("65535").unwrap(), 65535);
+        assert!(validate_port("0").is_err());
+        assert!(validate_port("65536").is_err());
+        assert!(validate_port("invalid").is_err());
+        assert!(validate_port("-1").is_err());
+    }
+
+    #[test]
+    fn test_validate_ports() {
+        let ports = ["3001".to_string(), "3002".to_string()];
+        assert_eq!(validate_ports(&ports).unwrap(), vec![3001, 3002]);
+
+        let ports = ["3001".to_string(), "0".to_string()];
+        assert!(validate_ports(&ports).unwrap_err().contains("'0'"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_privileged_port_warning() {
+        assert!(privileged_port_warning(80, 1024, false).unwrap().contains("Port 80"));
+        assert!(privileged_port_warning(1023, 1024, false).is_some());
+        assert!(privileged_port_warning(1024, 1024, false).is_none());
+        assert!(privileged_port_warning(80, 1024, true).is_none());
+        assert!(privileged_port_warning(80, 0, false).is_none());
+    }
+
+    #[test]
+    fn test_validate_byte_size() {
+        assert_eq!(validate_byte_size("1024").unwrap(), 1024);
+        assert_eq!(validate_byte_size("512KB").unwrap(), 512 * 1024);
+        assert_eq!(validate_byte_size("10mb").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(validate_byte_size("1 GB").unwrap(), 1024 * 1024 * 1024);
+        assert!(validate_byte_size("0").is_err());
+        assert!(validate_byte_size("ten").is_err());
+        assert!(validate_byte_size("10TB").is_err());
+        assert!(validate_byte_size("MB").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_config_delay_cap() {
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { max_delay_ms: 10, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/slow")
+            .insert_header((INTERNAL_DELAY_MS_HEADER, "50"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/slow")
+            .insert_header((INTERNAL_DELAY_MS_HEADER, "10"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_max_body_size() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(Config { max_body_size: 8, ..Config::default() }))
+                .app_data(web::PayloadConfig::new(8)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post().uri("/upload").set_payload("12345678").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let req = actix_web::test::TestRequest::post().uri("/upload").set_payload("123456789").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            actix_web::test::read_body(resp).await,
+            "Request body exceeds the maximum size of 8 bytes"
+        );
+    }
+
+    #[test]
+    fn test_validate_auth() {
+        assert_eq!(validate_auth("user:pass").unwrap(), "user:pass");
+        assert_eq!(validate_auth("user:").unwrap(), "user:");
+        assert_eq!(validate_auth("user:pa:ss").unwrap(), "user:pa:ss");
+        assert!(validate_auth("user").is_err());
+        assert!(validate_auth(":pass").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_serve_once() {
+        let once = web::Data::new(ServeOnce::new(true));
+        let app = actix_web::test::init_service(App::new().configure(test_config).app_data(once.clone())).await;
+
+        // Endpoints other than the echo don't count
+        let req = actix_web::test::TestRequest::get().uri(DEFAULT_HEALTH_PATH).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_web::test::TestRequest::post().uri("/first").set_payload("hello").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await, "hello");
+        actix_web::rt::time::timeout(Duration::from_secs(1), once.served.notified())
+            .await
+            .expect("shutdown wasn't triggered");
+
+        let req = actix_web::test::TestRequest::post().uri("/second").set_payload("hello").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_mask_credentials() {
+        assert_eq!(mask_credentials("user:secret"), "user:********");
+        assert_eq!(mask_credentials("user:"), "user:********");
+        assert_eq!(mask_credentials("secret"), "********");
+    }
+
+    #[actix_web::test]
+    async fn test_config_endpoint() {
+        let config = ServerConfig {
+            bind_addresses: vec!["127.0.0.1:8001".parse().unwrap()],
+            workers: 4,
+            auth: Some("user:secret".to_string()),
+            default_format: ResponseFormat::Json,
+            ..ServerConfig::default()
+        };
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(config)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri(CONFIG_PATH).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_web::test::read_body(resp).await;
+        assert!(!String::from_utf8_lossy(&body).contains("secret"));
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["bind_addresses"][0], "127.0.0.1:8001");
+        assert_eq!(json["workers"], 4);
+        assert_eq!(json["max_body_size"], DEFAULT_MAX_BODY_SIZE);
+        assert_eq!(json["auth"], "user:********");
+        assert_eq!(json["default_format"], "json");
+    }
+
+    #[actix_web::test]
+    async fn test_basic_auth() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(BasicAuth(Some("user:pass".to_string())))),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post().uri("/secret").set_payload("hello").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers().get("www-authenticate").unwrap(), "Basic realm=\"grecho\"");
+
+        // "user:wrong"
+        let req = actix_web::test::TestRequest::post()
+            .uri("/secret")
+            .insert_header(("authorization", "Basic dXNlcjp3cm9uZw=="))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+
+        // "user:pass"
+        let req = actix_web::test::TestRequest::post()
+            .uri("/secret")
+            .insert_header(("authorization", "Basic dXNlcjpwYXNz"))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert!(resp.headers().get("authorization").is_none());
+        assert_eq!(actix_web::test::read_body(resp).await, "hello");
+
+        let req = actix_web::test::TestRequest::get().uri(DEFAULT_HEALTH_PATH).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_validate_status() {
+        assert_eq!(validate_status("200").unwrap(), StatusCode::OK);
+        assert_eq!(validate_status("503").unwrap(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(validate_status("299").unwrap().as_u16(), 299);
+        assert!(validate_status("99").is_err());
+        assert!(validate_status("600").is_err());
+        assert!(validate_status("ok").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_default_status() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(Config { default_status: StatusCode::ACCEPTED, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post().uri("/job").set_payload("queued").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(actix_web::test::read_body(resp).await, "queued");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/job")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "500"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn test_default_content_type() {
+        let default_content_type = Some(HeaderValue::from_static("application/octet-stream"));
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(Config { default_content_type, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post().uri("/blob").set_payload("data").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/octet-stream");
+
+        // The request's Content-Type is echoed instead
+        let req = actix_web::test::TestRequest::post()
+            .uri("/blob")
+            .insert_header(("content-type", "text/csv"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/csv");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/blob")
+            .insert_header((INTERNAL_CONTENT_TYPE_HEADER, "application/xml"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/xml");
+
+        assert!(validate_content_type("text/plain; charset=utf-8").is_ok());
+        assert!(validate_content_type("not a mime").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_log_file() {
+        let path = std::env::temp_dir().join(format!("grecho-log-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let verbose_log = VerboseLog::open(path.to_str().unwrap()).unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(Config { verbose: true, ..Config::default() }))
+                .app_data(web::Data::new(verbose_log)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post().uri("/logged").set_payload("hello").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(content.contains("📥 INCOMING REQUEST:"));
+        assert!(content.contains("   POST /logged"));
+        assert!(content.contains("📤 OUTGOING RESPONSE:"));
+        assert!(content.contains("   Body: hello"));
+    }
+
+    #[actix_web::test]
+    async fn test_record() {
+        let path = std::env::temp_dir().join(format!("grecho-record-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Recorder::open(path.to_str().unwrap()).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(recorder)),
+        )
+        .await;
+
+        for body in ["first", "second"] {
+            let req = actix_web::test::TestRequest::post()
+                .uri("/orders?id=1")
+                .insert_header(("x-custom", "value"))
+                .set_payload(body)
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        // Lines are written in the background
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = std::fs::read_to_string(&path).unwrap_or_default();
+            if content.lines().count() == 2 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let lines: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["method"], "POST");
+        assert_eq!(lines[0]["path"], "/orders");
+        assert_eq!(lines[0]["query"], "id=1");
+        assert_eq!(lines[0]["headers"]["x-custom"], "value");
+        assert_eq!(lines[0]["body"], "first");
+        assert_eq!(lines[1]["body"], "second");
+        assert!(lines[0]["timestamp"].as_f64().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/users", "/users"));
+        assert!(!glob_match("/users", "/users/1"));
+        assert!(glob_match("/users/*", "/users/1"));
+        assert!(glob_match("/users/*", "/users/"));
+        assert!(!glob_match("/users/*", "/users/1/orders"));
+        assert!(glob_match("/users/*/orders", "/users/1/orders"));
+        assert!(glob_match("/api/**", "/api/v1/users/1"));
+        assert!(glob_match("/**/health", "/a/b/health"));
+        assert!(glob_match("/file?.txt", "/file1.txt"));
+        assert!(!glob_match("/file?.txt", "/file.txt"));
+        assert!(glob_match("/*.json", "/data.json"));
+        assert!(!glob_match("/*.json", "/data.xml"));
+    }
+
+    #[actix_web::test]
+    async fn test_routes() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [[routes]]
+            path = "/api/users/*"
+            body = '{"id": 1}'
+
+            [[routes]]
+            path = "/api/**"
+            status = 404
+            body = "not found"
+
+            [[routes]]
+            path = "/created"
+            status = 201
+            "#,
+        )
+        .unwrap();
+        let routes = Routes::new(&settings.routes).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { routes, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri("/api/users/1").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await, r#"{"id": 1}"#);
+
+        let req = actix_web::test::TestRequest::get().uri("/api/orders/1").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(actix_web::test::read_body(resp).await, "not found");
+
+        // A route without a body still echoes
+        let req = actix_web::test::TestRequest::post().uri("/created").set_payload("echoed").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(actix_web::test::read_body(resp).await, "echoed");
+
+        // Internal headers still take precedence
+        let req = actix_web::test::TestRequest::get()
+            .uri("/api/orders/1")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "200"))
+            .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "override"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await, "override");
+
+        let req = actix_web::test::TestRequest::post().uri("/other").set_payload("echoed").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(actix_web::test::read_body(resp).await, "echoed");
+
+        let settings: Settings = toml::from_str("[[routes]]\npath = \"/bad\"\nstatus = 42").unwrap();
+        assert!(Routes::new(&settings.routes).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_rules() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [[rules]]
+            path_regex = "^/users/[0-9]+$"
+            echo = true
+
+            [[rules]]
+            path_regex = "^/users/"
+            status = 404
+            body = "no such user"
+
+            [[rules]]
+            path_regex = "\\.php$"
+            status = 403
+            "#,
+        )
+        .unwrap();
+        let rules = Rules::new(&settings.rules).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { rules, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post().uri("/users/42").set_payload("echoed").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await, "echoed");
+
+        let req = actix_web::test::TestRequest::post().uri("/users/me").set_payload("echoed").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(actix_web::test::read_body(resp).await, "no such user");
+
+        let req = actix_web::test::TestRequest::get().uri("/admin/index.php").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(actix_web::test::read_body(resp).await.is_empty());
+
+        let req = actix_web::test::TestRequest::post().uri("/other").set_payload("echoed").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(actix_web::test::read_body(resp).await, "echoed");
+    }
+
+    #[actix_web::test]
+    async fn test_matched_rule_header() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [[rules]]
+            path_regex = "^/users/[0-9]+$"
+            echo = true
+
+            [[rules]]
+            name = "unknown user"
+            path_regex = "^/users/"
+            status = 404
+
+            [[routes]]
+            path = "/users/*"
+            status = 201
+            "#,
+        )
+        .unwrap();
+        let config = |matched_rule_header| Config {
+            routes: Routes::new(&settings.routes).unwrap(),
+            rules: Rules::new(&settings.rules).unwrap(),
+            matched_rule_header,
+            ..Config::default()
+        };
+        let app = actix_web::test::init_service(App::new().configure(test_config).app_data(web::Data::new(config(true)))).await;
+
+        let matched_rule = |resp: &actix_web::dev::ServiceResponse| {
+            resp.headers().get(MATCHED_RULE_HEADER).map(|v| v.to_str().unwrap().to_string())
+        };
+        for (uri, status, expected) in [
+            ("/users/42", StatusCode::CREATED, "rules[0] ^/users/[0-9]+$, routes[0] /users/*"),
+            ("/users/me", StatusCode::NOT_FOUND, "rules[1] unknown user"),
+            ("/orders", StatusCode::OK, "echo"),
+        ] {
+            let req = actix_web::test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+            assert_eq!(matched_rule(&resp).as_deref(), Some(expected), "{}", uri);
+        }
+
+        let app = actix_web::test::init_service(App::new().configure(test_config).app_data(web::Data::new(config(false)))).await;
+        let req = actix_web::test::TestRequest::get().uri("/users/me").to_request();
+        assert_eq!(matched_rule(&actix_web::test::call_service(&app, req).await), None);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let rule = |toml: &str| Rules::new(&toml::from_str::<Settings>(toml).unwrap().rules);
+        assert!(rule("[[rules]]\npath_regex = \"^/(unclosed\"\nstatus = 404").unwrap_err().contains("Invalid path_regex"));
+        assert!(rule("[[rules]]\npath_regex = \"^/\"").is_err());
+        assert!(rule("[[rules]]\npath_regex = \"^/\"\necho = true\nstatus = 404").is_err());
+        assert!(rule("[[rules]]\npath_regex = \"^/\"\nstatus = 42").is_err());
+        assert!(rule("[[rules]]\npath_regex = \"^/\"\nbody = \"ok\"").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_replay() {
+        let file: ReplayFile = toml::from_str(
+            r#"
+            [[responses]]
+            method = "get"
+            path = "/users/1"
+            body = '{"id": 1}'
+            headers = { content-type = "application/json", x-mock = "true" }
+
+            [[responses]]
+            path = "/down"
+            status = 503
+            "#,
+        )
+        .unwrap();
+        let replay = Replay::from_entries(file.responses).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { replay, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri("/users/1").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/json");
+        assert_eq!(resp.headers().get("x-mock").unwrap(), "true");
+        assert_eq!(actix_web::test::read_body(resp).await, r#"{"id": 1}"#);
+
+        let req = actix_web::test::TestRequest::delete().uri("/down").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Other methods and paths fall back to the echo
+        let req = actix_web::test::TestRequest::post().uri("/users/1").set_payload("echoed").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await, "echoed");
+
+        let file: ReplayFile = serde_json::from_str(r#"{"responses": [{"path": "/bad", "status": 42}]}"#).unwrap();
+        assert!(Replay::from_entries(file.responses).is_err());
+        let file: ReplayFile = serde_json::from_str(r#"{"responses": [{"path": "/bad", "headers": {"bad name": "x"}}]}"#).unwrap();
+        assert!(Replay::from_entries(file.responses).is_err());
+    }
+
+    #[test]
+    fn test_replay_load_extension() {
+        // The extension is matched case-insensitively
+        let path = std::env::temp_dir().join(format!("grecho-replay-{}.JSON", std::process::id()));
+        std::fs::write(&path, r#"{"responses": [{"path": "/users/1", "body": "one"}]}"#).unwrap();
+        let replay = Replay::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replay.unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn test_validate_workers() {
+        assert_eq!(validate_workers("1").unwrap(), 1);
+        assert_eq!(validate_workers("16").unwrap(), 16);
+        assert!(validate_workers("0").is_err());
+        assert!(validate_workers("-2").is_err());
+        assert!(validate_workers("many").is_err());
+    }
+
+    #[test]
+    fn test_resolve_bind_addresses_precedence() {
+        let settings = Settings {
+            host: "127.0.0.3".to_string(),
+            port: 3003,
+            ..Settings::default()
+        };
+        let resolve = |args: &[&str]| {
+            let matches = build_cli().try_get_matches_from(args).unwrap();
+            resolve_bind_addresses(&matches, &settings, DEFAULT_SETTINGS_PATH)
+        };
+
+        std::env::remove_var(HOST_ENV_VAR);
+        std::env::remove_var(PORT_ENV_VAR);
+        assert_eq!(resolve(&["grecho"]).unwrap().0, vec!["127.0.0.3:3003".parse().unwrap()]);
+
+        std::env::set_var(HOST_ENV_VAR, "127.0.0.2");
+        std::env::set_var(PORT_ENV_VAR, "2002,2003");
+        assert_eq!(
+            resolve(&["grecho"]).unwrap().0,
+            vec!["127.0.0.2:2002".parse().unwrap(), "127.0.0.2:2003".parse().unwrap()]
+        );
+        assert_eq!(
+            resolve(&["grecho", "-n", "127.0.0.1", "-p", "1001"]).unwrap(),
+            (vec!["127.0.0.1:1001".parse().unwrap()], Vec::new())
+        );
+        assert_eq!(
+            resolve(&["grecho", "-n", "127.0.0.1", "-p", "1001", "--https-port", "1443"]).unwrap(),
+            (vec!["127.0.0.1:1001".parse().unwrap()], vec!["127.0.0.1:1443".parse().unwrap()])
+        );
+
+        std::env::set_var(PORT_ENV_VAR, "0");
+        assert!(resolve(&["grecho"]).unwrap_err().contains(PORT_ENV_VAR));
+
+        std::env::remove_var(HOST_ENV_VAR);
+        std::env::remove_var(PORT_ENV_VAR);
+    }
+
+    #[test]
+    fn test_validate_path() {
+        assert_eq!(validate_path("/health").unwrap(), "/health");
+        assert_eq!(validate_path("/").unwrap(), "/");
+        assert!(validate_path("health").is_err());
+        assert!(validate_path("").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_health_endpoint_is_not_echoed() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(DEFAULT_HEALTH_PATH)
+            .insert_header(("x-custom", "value"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert!(resp.headers().get("x-custom").is_none());
+        let body = actix_web::test::read_body(resp).await;
+        assert_eq!(body, r#"{"status":"ok"}"#);
+    }
+
+    #[test]
+    fn test_load_tls_config() {
+        assert!(load_tls_config("tests/fixtures/cert.pem", "tests/fixtures/key.pem", None).is_ok());
+        assert!(load_tls_config("tests/fixtures/missing.pem", "tests/fixtures/key.pem", None).is_err());
+        assert!(load_tls_config("tests/fixtures/cert.pem", "tests/fixtures/missing.pem", None).is_err());
+        assert!(load_tls_config("tests/fixtures/key.pem", "tests/fixtures/cert.pem", None).is_err());
+        let client_ca = Some("tests/fixtures/client-ca.pem");
+        assert!(load_tls_config("tests/fixtures/cert.pem", "tests/fixtures/key.pem", client_ca).is_ok());
+        assert!(load_tls_config("tests/fixtures/cert.pem", "tests/fixtures/key.pem", Some("tests/fixtures/key.pem")).is_err());
+        // The key has to be the one of the certificate
+        assert!(load_tls_config("tests/fixtures/cert.pem", "tests/fixtures/server-key.pem", None).is_err());
+    }
+
+    #[test]
+    fn test_watch_tls_files() {
+        let dir = std::env::temp_dir().join(format!("grecho-tls-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::copy("tests/fixtures/cert.pem", &cert_path).unwrap();
+        std::fs::copy("tests/fixtures/key.pem", &key_path).unwrap();
+        let (cert_path, key_path) = (cert_path.to_str().unwrap(), key_path.to_str().unwrap());
+
+        let (_, certificate) = load_tls_config(cert_path, key_path, None).unwrap();
+        let _watcher = watch_tls_files(cert_path, key_path, certificate.clone()).unwrap();
+        let served = |expected: &str| certificate.current().cert[0] == CertificateDer::from_pem_file(expected).unwrap();
+        let wait_for = |expected: &str| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !served(expected) && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            served(expected)
+        };
+        assert!(served("tests/fixtures/cert.pem"));
+
+        std::fs::copy("tests/fixtures/server-cert.pem", cert_path).unwrap();
+        std::fs::copy("tests/fixtures/server-key.pem", key_path).unwrap();
+        assert!(wait_for("tests/fixtures/server-cert.pem"));
+
+        // A broken certificate keeps the previous one
+        std::fs::write(cert_path, "not a certificate").unwrap();
+        std::thread::sleep(TLS_RELOAD_SETTLE_TIME * 3);
+        assert!(served("tests/fixtures/server-cert.pem"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_certificate_subject_cn() {
+        let cn = |path: &str| certificate_subject_cn(&CertificateDer::from_pem_file(path).unwrap());
+        assert_eq!(cn("tests/fixtures/client-cert.pem").as_deref(), Some("grecho-test-client"));
+        assert_eq!(cn("tests/fixtures/cert.pem").as_deref(), Some("localhost"));
+        assert_eq!(certificate_subject_cn(b"\x30\x82\x01"), None);
+    }
+
+    #[test]
+    fn test_response_format_from_str() {
+        assert_eq!("json".parse::<ResponseFormat>().unwrap(), ResponseFormat::Json);
+        assert_eq!(" JSON ".parse::<ResponseFormat>().unwrap(), ResponseFormat::Json);
+        assert_eq!("echo".parse::<ResponseFormat>().unwrap(), ResponseFormat::Echo);
+        assert_eq!("multipart-summary".parse::<ResponseFormat>().unwrap(), ResponseFormat::MultipartSummary);
+        assert_eq!("hexdump".parse::<ResponseFormat>().unwrap(), ResponseFormat::Hexdump);
+        assert!("xml".parse::<ResponseFormat>().is_err());
+    }
+
+    #[test]
+    fn test_throughput_report() {
+        assert_eq!(
+            throughput_report(12, 1012, Duration::from_secs(5)),
+            "📈 2.4 req/s over the last 5s (12 requests), 1012 in total"
+        );
+    }
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(
+            hexdump(b"hello world\n\x00\xffabcdefghijklmnopq"),
+            "00000000  68 65 6c 6c 6f 20 77 6f  72 6c 64 0a 00 ff 61 62  |hello world...ab|\n\
+             00000010  63 64 65 66 67 68 69 6a  6b 6c 6d 6e 6f 70 71     |cdefghijklmnopq|\n\
+             0000001f\n"
+        );
+        assert_eq!(
+            hexdump(b"hi"),
+            "00000000  68 69                                             |hi|\n00000002\n"
+        );
+        assert_eq!(hexdump(b""), "");
+    }
+
+    #[actix_web::test]
+    async fn test_hexdump_format() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/upload")
+            .insert_header((INTERNAL_FORMAT_HEADER, "hexdump"))
+            .set_payload(&b"\x00hi"[..])
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+        let body = actix_web::test::read_body(resp).await;
+        assert!(body.starts_with(b"00000000  00 68 69 "));
+    }
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("Text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_color_mode() {
+        assert_eq!("Always".parse::<ColorMode>().unwrap(), ColorMode::Always);
+        assert_eq!(" never ".parse::<ColorMode>().unwrap(), ColorMode::Never);
+        assert!("yes".parse::<ColorMode>().is_err());
+        assert!(ColorMode::Always.enabled(false));
+        assert!(!ColorMode::Never.enabled(true));
+        // A log file is never a terminal
+        assert!(!ColorMode::Auto.enabled(false));
+
+        assert_eq!(paint(true, status_style(503), "503"), "\x1b[1;31m503\x1b[0m");
+        assert_eq!(paint(false, status_style(503), "503"), "503");
+        assert_eq!(status_style(201), ANSI_GREEN);
+        assert_eq!(status_style(404), ANSI_YELLOW);
+    }
+
+    #[test]
+    fn test_access_log_format_from_str() {
+        assert_eq!("clf".parse::<AccessLogFormat>().unwrap(), AccessLogFormat::Clf);
+        assert_eq!("Common".parse::<AccessLogFormat>().unwrap(), AccessLogFormat::Clf);
+        assert_eq!(" combined ".parse::<AccessLogFormat>().unwrap(), AccessLogFormat::Combined);
+        assert!("json".parse::<AccessLogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_clf_timestamp() {
+        assert_eq!(clf_timestamp(UNIX_EPOCH + Duration::from_secs(971_186_136)), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_timestamp(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+    }
+
+    #[actix_web::test]
+    async fn test_raw_request() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/raw/path?a=1&b=2")
+            .insert_header((INTERNAL_RAW_HEADER, "true"))
+            .insert_header(("user-agent", "raw-test"))
+            .insert_header(("x-custom", "value"))
+            .set_payload("the body")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+        let body = actix_web::test::read_body(resp).await;
+        let raw = String::from_utf8(body.to_vec()).unwrap();
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        assert_eq!(lines.next().unwrap(), "POST /raw/path?a=1&b=2 HTTP/1.1");
+        let headers: Vec<&str> = lines.collect();
+        // Reserved and internal headers are part of the capture
+        assert!(headers.contains(&"user-agent: raw-test"));
+        assert!(headers.contains(&"internal.raw: true"));
+        assert!(headers.contains(&"x-custom: value"));
+        assert!(headers.contains(&"content-length: 8"));
+        assert_eq!(body, "the body");
+    }
+
+    #[actix_web::test]
+    async fn test_json_format() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/items?id=7")
+            .insert_header((INTERNAL_FORMAT_HEADER, "json"))
+            .insert_header(("x-custom", "value"))
+            .set_payload(vec![0xff, 0x00])
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/json");
+        let json: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(json["method"], "POST");
+        assert_eq!(json["path"], "/items");
+        assert_eq!(json["query"], "id=7");
+        assert_eq!(json["headers"]["x-custom"], "value");
+        assert_eq!(json["body"], "/wA=");
+        assert_eq!(json["body_encoding"], "base64");
+    }
+
+    #[test]
+    fn test_checksum_algorithm() {
+        assert_eq!(
+            ChecksumAlgorithm::Sha256.hex_digest(b"hello"),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(ChecksumAlgorithm::Md5.hex_digest(b"hello"), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(ChecksumAlgorithm::Crc32.hex_digest(b"hello"), "3610a686");
+        assert_eq!(ChecksumAlgorithm::Crc32.hex_digest(b""), "00000000");
+        assert_eq!(" SHA256 ".parse::<ChecksumAlgorithm>(), Ok(ChecksumAlgorithm::Sha256));
+        assert!("sha1".parse::<ChecksumAlgorithm>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_checksum() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/upload")
+            .insert_header((INTERNAL_CHECKSUM_HEADER, "md5"))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(BODY_CHECKSUM_HEADER).unwrap(), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(actix_web::test::read_body(resp).await, "5d41402abc4b2a76b9719d911017c592");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/upload")
+            .insert_header((INTERNAL_CHECKSUM_HEADER, "sha1"))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_server_timing() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let req = actix_web::test::TestRequest::get().uri("/test").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(SERVER_TIMING_HEADER));
+
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { server_timing: true, ..Config::default() })),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_DELAY_MS_HEADER, "20"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        let timing = resp.headers().get(SERVER_TIMING_HEADER).unwrap().to_str().unwrap();
+        let (total, delay) = timing.split_once(", ").unwrap();
+        let total: f64 = total.strip_prefix("total;dur=").unwrap().parse().unwrap();
+        assert!(total >= 20.0, "{}", timing);
+        assert_eq!(delay, "delay;dur=20");
+
+        assert_eq!(server_timing(Duration::from_micros(1260), None), "total;dur=1.3");
+    }
+
+    #[actix_web::test]
+    async fn test_force_encoding() {
+        use std::io::Read;
+
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/test")
+            .insert_header((INTERNAL_FORCE_ENCODING_HEADER, "gzip"))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        let body = actix_web::test::read_body(resp).await;
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "hello");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/test")
+            .insert_header((INTERNAL_FORCE_ENCODING_HEADER, "br"))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_multipart_summary() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let body = "--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            hello\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"a.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            0123456789\r\n\
+            --XyZ--\r\n";
+        let req = actix_web::test::TestRequest::post()
+            .uri("/upload")
+            .insert_header((INTERNAL_FORMAT_HEADER, "multipart-summary"))
+            .insert_header(("content-type", "multipart/form-data; boundary=XyZ"))
+            .set_payload(body)
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/json");
+        let json: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(
+            json,
+            serde_json::json!({"parts": [
+                {"name": "title", "filename": null, "content_type": null, "size": 5},
+                {"name": "upload", "filename": "a.png", "content_type": "image/png", "size": 10},
+            ]})
+        );
+
+        for content_type in ["text/plain", "multipart/form-data; boundary=other"] {
+            let req = actix_web::test::TestRequest::post()
+                .uri("/upload")
+                .insert_header((INTERNAL_FORMAT_HEADER, "multipart-summary"))
+                .insert_header(("content-type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", content_type);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_stats_count_and_reset() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        for req in [
+            actix_web::test::TestRequest::get().uri("/a"),
+            actix_web::test::TestRequest::get().uri("/b"),
+            actix_web::test::TestRequest::post().uri("/c"),
+        ] {
+            actix_web::test::call_service(&app, req.to_request()).await;
+        }
+
+        let req = actix_web::test::TestRequest::get().uri(STATS_PATH).to_request();
+        let json: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(json["total"], 3);
+        assert_eq!(json["methods"]["GET"], 2);
+        assert_eq!(json["methods"]["POST"], 1);
+        assert_eq!(json["paths"], serde_json::json!({"/a": 1, "/b": 1, "/c": 1}));
+        assert_eq!(json["untracked_paths"], 0);
+
+        let req = actix_web::test::TestRequest::delete().uri(STATS_PATH).to_request();
+        actix_web::test::call_service(&app, req).await;
+
+        let req = actix_web::test::TestRequest::get().uri(STATS_PATH).to_request();
+        let json: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(json["total"], 0);
+        assert!(json["methods"].as_object().unwrap().is_empty());
+        assert!(json["paths"].as_object().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_stats_path_cap() {
+        let stats = Stats::default();
+        for i in 0..MAX_TRACKED_PATHS + 2 {
+            stats.record(&actix_web::test::TestRequest::get().uri(&format!("/p{}?q=1", i)).to_http_request());
+        }
+        stats.record(&actix_web::test::TestRequest::get().uri("/p0").to_http_request());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total, MAX_TRACKED_PATHS as u64 + 3);
+        assert_eq!(snapshot.paths.len(), MAX_TRACKED_PATHS);
+        assert_eq!(snapshot.paths["/p0"], 2);
+        assert_eq!(snapshot.untracked_paths, 2);
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let network: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+
+        let network: IpNet = "fd00::/8".parse().unwrap();
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        assert!(!network.contains("fe80::1".parse().unwrap()));
+
+        let host: IpNet = "192.168.1.5".parse().unwrap();
+        assert!(host.contains("192.168.1.5".parse().unwrap()));
+        assert!(!host.contains("192.168.1.6".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_allow_ip() {
+        let allowed: SocketAddr = "10.0.0.7:5000".parse().unwrap();
+        let denied: SocketAddr = "192.168.0.7:5000".parse().unwrap();
+        let request = |peer: SocketAddr, forwarded_for: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::get().uri("/").peer_addr(peer);
+            if let Some(forwarded_for) = forwarded_for {
+                req = req.insert_header(("x-forwarded-for", forwarded_for));
+            }
+            req.to_request()
+        };
+
+        let allowlist = IpAllowlist::new(&["10.0.0.0/24".to_string()], false).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().wrap(from_fn(require_allowed_ip)).configure(test_config).app_data(web::Data::new(allowlist)),
+        )
+        .await;
+        assert_eq!(actix_web::test::call_service(&app, request(allowed, None)).await.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::call_service(&app, request(denied, None)).await.status(), StatusCode::FORBIDDEN);
+        // The header is ignored unless it is trusted
+        let resp = actix_web::test::call_service(&app, request(denied, Some("10.0.0.1"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = actix_web::test::TestRequest::get().uri(DEFAULT_HEALTH_PATH).peer_addr(denied).to_request();
+        assert_eq!(actix_web::test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let allowlist = IpAllowlist::new(&["10.0.0.0/24".to_string()], true).unwrap();
+        let app = actix_web::test::init_service(
+            App::new().wrap(from_fn(require_allowed_ip)).configure(test_config).app_data(web::Data::new(allowlist)),
+        )
+        .await;
+        // The last entry is the one the proxy appended, the first one is up to the client
+        let resp = actix_web::test::call_service(&app, request(denied, Some("192.168.0.1, 10.0.0.9"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = actix_web::test::call_service(&app, request(allowed, Some("10.0.0.9, 192.168.0.1"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(actix_web::test::call_service(&app, request(allowed, None)).await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::default().check("").is_ok());
+
+        let limiter = RateLimiter::new(Some(2), true);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry_after = limiter.check("a").unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(500));
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit_response() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(RateLimiter::new(Some(1), false))),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get().uri("/limited").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let req = actix_web::test::TestRequest::get().uri("/limited").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_validate_location() {
+        assert_eq!(validate_location("/login").unwrap(), "/login");
+        assert_eq!(validate_location("https://example.com/a?b=c").unwrap(), "https://example.com/a?b=c");
+        assert!(validate_location("http://localhost:8001").is_ok());
+        assert!(validate_location("login").is_err());
+        assert!(validate_location("//example.com").is_err());
+        assert!(validate_location("ftp://example.com").is_err());
+        assert!(validate_location("").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_head_request() {
+        let (addr, handle) = start_test_server();
+
+        let response = raw_request(
+            addr,
+            "HEAD /probe HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nx-custom: value\r\ninternal.status-code: 202\r\ninternal.response-body: hello world\r\n\r\n",
+        )
+        .await;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let head = head.to_lowercase();
+        assert!(head.starts_with("http/1.1 202 accepted"));
+        assert!(head.contains("content-length: 11"));
+        assert!(head.contains("x-custom: value"));
+        assert!(body.is_empty());
+
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let req = actix_web::test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/probe")
+            .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "hello world"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.response().body().size(), BodySize::Sized(11));
+        assert!(actix_web::test::read_body(resp).await.is_empty());
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_response_body_base64() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_RESPONSE_BODY_BASE64_HEADER, "AP/+gA=="))
+            .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "plain"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(actix_web::test::read_body(resp).await, &[0x00, 0xff, 0xfe, 0x80][..]);
+
+        // Invalid base64 falls back to the plain override, unless in strict mode
+        let req = || {
+            actix_web::test::TestRequest::get()
+                .uri("/test")
+                .insert_header((INTERNAL_RESPONSE_BODY_BASE64_HEADER, "not base64!"))
+                .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "plain"))
+                .to_request()
+        };
+        let resp = actix_web::test::call_service(&app, req()).await;
+        assert_eq!(actix_web::test::read_body(resp).await, "plain");
+
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { strict: true, ..Config::default() })),
+        )
+        .await;
+        let resp = actix_web::test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_binary_body() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let payload: &[u8] = &[0x00, 0xff, 0xfe, 0x80, 0x0a, 0xc3, 0x28];
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("content-type", "application/octet-stream"))
+            .set_payload(payload)
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await.as_ref(), payload);
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/upload")
+            .insert_header((INTERNAL_REPEAT_BODY_HEADER, "2"))
+            .set_payload(payload)
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(actix_web::test::read_body(resp).await.as_ref(), payload.repeat(2).as_slice());
+    }
+
+    #[test]
+    fn test_parse_status_list() {
+        assert_eq!(
+            parse_status_list("200, 500,503").unwrap(),
+            vec![StatusCode::OK, StatusCode::INTERNAL_SERVER_ERROR, StatusCode::SERVICE_UNAVAILABLE]
+        );
+        assert_eq!(parse_status_list("404,").unwrap(), vec![StatusCode::NOT_FOUND]);
+        assert!(parse_status_list("").is_err());
+        assert!(parse_status_list("200,abc").is_err());
+        assert!(parse_status_list("200,700").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_random_status() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            let req = actix_web::test::TestRequest::get()
+                .uri("/chaos")
+                .insert_header((INTERNAL_RANDOM_STATUS_HEADER, "200,500,503"))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            seen.insert(resp.status().as_u16());
+        }
+        assert_eq!(seen, HashSet::from([200, 500, 503]));
+
+        // internal.status-code still wins
+        let req = actix_web::test::TestRequest::get()
+            .uri("/chaos")
+            .insert_header((INTERNAL_RANDOM_STATUS_HEADER, "500,503"))
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "201"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/chaos")
+            .insert_header((INTERNAL_RANDOM_STATUS_HEADER, "500,nope"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_render_template() {
+        let req = actix_web::test::TestRequest::post()
+            .uri("/orders?id=42&debug")
+            .insert_header(("x-foo", "bar"))
+            .to_http_request();
+
+        assert_eq!(
+            render_template("{method} {path}?{query} {body}", &req, b"payload"),
+            "POST /orders?id=42&debug payload"
+        );
+        assert_eq!(render_template("foo={header.x-foo} id={query.id}", &req, b""), "foo=bar id=42");
+        assert_eq!(render_template("[{header.x-missing}][{query.missing}]", &req, b""), "[][]");
+        assert_eq!(render_template("{unknown} {header.x-foo", &req, b""), "{unknown} {header.x-foo");
+        assert_eq!(render_template("{{\"path\": \"{path}\"}}", &req, b""), "{\"path\": \"/orders\"}");
+        assert_eq!(render_template("}{", &req, b""), "}{");
+    }
+
+    #[actix_web::test]
+    async fn test_template() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::put()
+            .uri("/items/7")
+            .insert_header(("x-tenant", "acme"))
+            .insert_header((INTERNAL_TEMPLATE_HEADER, "{method} {path} for {header.x-tenant}: {body}"))
+            .set_payload("hello")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(INTERNAL_TEMPLATE_HEADER).is_none());
+        assert_eq!(actix_web::test::read_body(resp).await, "PUT /items/7 for acme: hello");
+    }
+
+    #[actix_web::test]
+    async fn test_rename_header() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/gateway")
+            .insert_header(("x-request-id", "abc"))
+            .insert_header(("authorization", "Bearer token"))
+            .append_header((INTERNAL_RENAME_HEADER_HEADER, "x-request-id:x-correlation-id"))
+            .append_header((INTERNAL_RENAME_HEADER_HEADER, "Authorization : X-Forwarded-Auth"))
+            .append_header((INTERNAL_RENAME_HEADER_HEADER, "not valid"))
+            .append_header((INTERNAL_RENAME_HEADER_HEADER, "x-missing:x-other"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("x-request-id").unwrap(), "abc");
+        assert_eq!(resp.headers().get("x-correlation-id").unwrap(), "abc");
+        assert_eq!(resp.headers().get("x-forwarded-auth").unwrap(), "Bearer token");
+        assert!(resp.headers().get("authorization").is_none());
+        assert!(resp.headers().get("x-other").is_none());
+
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { strict: true, ..Config::default() })),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/gateway")
+            .insert_header((INTERNAL_RENAME_HEADER_HEADER, "not valid"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_truncate_body() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        for (value, expected) in [("5", "hello"), ("0", ""), ("100", "hello world"), ("x", "hello world")] {
+            let req = actix_web::test::TestRequest::post()
+                .uri("/test")
+                .insert_header((INTERNAL_TRUNCATE_BODY_HEADER, value))
+                .set_payload("hello world")
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(actix_web::test::read_body(resp).await, expected, "{}", value);
+        }
+
+        // Applies to overridden bodies too
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "override"))
+            .insert_header((INTERNAL_TRUNCATE_BODY_HEADER, "4"))
+            .to_request();
+        assert_eq!(actix_web::test::call_and_read_body(&app, req).await, "over");
+    }
+
+    #[actix_web::test]
+    async fn test_pad_bytes() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(Config { max_pad_bytes: 1000, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/download")
+            .insert_header((INTERNAL_PAD_BYTES_HEADER, "100"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+        let body = actix_web::test::read_body(resp).await;
+        assert_eq!(body.len(), 100);
+        assert!(body.iter().enumerate().all(|(i, byte)| *byte == PAD_PATTERN[i % PAD_PATTERN.len()]));
+        assert_eq!(&body[60..64], b"YZ01");
+
+        // Replaces the request body, and truncation still applies
+        let req = actix_web::test::TestRequest::post()
+            .uri("/download")
+            .insert_header((INTERNAL_PAD_BYTES_HEADER, "1000"))
+            .insert_header((INTERNAL_TRUNCATE_BODY_HEADER, "3"))
+            .set_payload("hello")
+            .to_request();
+        assert_eq!(actix_web::test::call_and_read_body(&app, req).await, "012");
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/download")
+            .insert_header((INTERNAL_PAD_BYTES_HEADER, "1001"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/download")
+            .insert_header((INTERNAL_PAD_BYTES_HEADER, "lots"))
+            .set_payload("hello")
+            .to_request();
+        assert_eq!(actix_web::test::call_and_read_body(&app, req).await, "hello");
+    }
+
+    #[actix_web::test]
+    async fn test_reflect_cookies() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_REFLECT_COOKIES_HEADER, "true"))
+            .append_header(("cookie", "session=abc; theme=dark;; invalid"))
+            .append_header(("cookie", "session=other; token=a=b"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/json");
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({"session": "abc", "theme": "dark", "token": "a=b"}));
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_REFLECT_COOKIES_HEADER, "true"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(actix_web::test::read_body(resp).await, "{}");
+    }
+
+    #[actix_web::test]
+    async fn test_reflect_query() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test?tag=a&name=grecho%20test&tag=b&empty=&tag=c")
+            .insert_header((INTERNAL_REFLECT_QUERY_HEADER, "true"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/json");
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({"tag": ["a", "b", "c"], "name": "grecho test", "empty": ""}));
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/test")
+            .insert_header((INTERNAL_REFLECT_QUERY_HEADER, "true"))
+            .set_payload("body")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(actix_web::test::read_body(resp).await, "{}");
+    }
+
+    #[actix_web::test]
+    async fn test_reflect_peer() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let peer_addr: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .peer_addr(peer_addr)
+            .insert_header((INTERNAL_REFLECT_PEER_HEADER, "true"))
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .insert_header((PEER_ADDR_HEADER, "10.0.0.1:1"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(PEER_ADDR_HEADER).unwrap(), "203.0.113.7:51234");
+
+        let req = actix_web::test::TestRequest::get().uri("/test").peer_addr(peer_addr).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(PEER_ADDR_HEADER));
+    }
+
+    #[actix_web::test]
+    async fn test_status_path() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        // The path wins over the header
+        let req = actix_web::test::TestRequest::get()
+            .uri("/status/503")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "418"))
+            .insert_header(("x-test", "1"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("x-test").unwrap(), "1");
+
+        for uri in ["/status/42", "/status/abc", "/status/", "/status/500/extra"] {
+            let req = actix_web::test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        for uri in ["/status", "/statuses/500", "/api/status/500"] {
+            let req = actix_web::test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+        }
+
+        // Rejected like the other controls in strict mode
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { strict: true, ..Config::default() })),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get().uri("/status/999").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/problem+json");
+    }
+
+    #[actix_web::test]
+    async fn test_http_version() {
+        use actix_web::http::Version;
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        for (version, value) in [(Version::HTTP_10, "1.0"), (Version::HTTP_11, "1.1")] {
+            let req = actix_web::test::TestRequest::get()
+                .uri("/test")
+                .version(version)
+                .insert_header((INTERNAL_HTTP_VERSION_HEADER, value))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", value);
+        }
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_HTTP_VERSION_HEADER, "1.0"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = actix_web::test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).starts_with("Can't answer a HTTP/1.1 request with HTTP/1.0"));
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header((INTERNAL_HTTP_VERSION_HEADER, "2"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_bench() {
+        let options = BenchOptions {
+            requests: 40,
+            concurrency: 4,
+            body_size: 100,
+        };
+        let report = bench(options).await.unwrap();
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.latency.unwrap().count, 40);
+        assert!(report.to_string().starts_with("🏁 40 requests with 100 byte bodies over 4 connections in "));
+
+        let matches = build_cli().try_get_matches_from(["grecho", "bench", "-n", "5", "-c", "2", "-b", "0"]).unwrap();
+        let bench_matches = matches.subcommand_matches("bench").unwrap();
+        assert_eq!(bench_matches.get_one::<u64>("requests"), Some(&5));
+        assert!(build_cli().try_get_matches_from(["grecho", "--port", "8080", "bench"]).is_err());
+        assert!(build_cli().try_get_matches_from(["grecho", "bench", "-c", "0"]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_duplicate_header() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test")
+            .insert_header(("x-custom", "a"))
+            .append_header((INTERNAL_DUPLICATE_HEADER_HEADER, "x-custom:3"))
+            .append_header((INTERNAL_DUPLICATE_HEADER_HEADER, "x-missing:2"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get_all("x-custom").collect::<Vec<_>>(), ["a", "a", "a"]);
+        assert!(!resp.headers().contains_key("x-missing"));
+        assert!(!resp.headers().contains_key(INTERNAL_DUPLICATE_HEADER_HEADER));
+
+        for entry in ["x-custom", "x-custom:0", "x-custom:101", "bad name:2"] {
+            let req = actix_web::test::TestRequest::get()
+                .uri("/test")
+                .insert_header(("x-custom", "a"))
+                .insert_header((INTERNAL_DUPLICATE_HEADER_HEADER, entry))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", entry);
+            let message = actix_web::test::read_body(resp).await;
+            let expected = format!("Invalid '{}' value '{}'.", INTERNAL_DUPLICATE_HEADER_HEADER, entry);
+            assert!(message.starts_with(expected.as_bytes()), "{}", entry);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_add_header() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/trace")
+            .insert_header(("x-trace", "from-request"))
+            .append_header((INTERNAL_ADD_HEADER_HEADER, "X-Trace: injected"))
+            .append_header((INTERNAL_ADD_HEADER_HEADER, "x-span: a"))
+            .append_header((INTERNAL_ADD_HEADER_HEADER, "X-Span:b"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let trace: Vec<_> = resp.headers().get_all("x-trace").collect();
+        assert_eq!(trace, vec!["injected"]);
+        let span: Vec<_> = resp.headers().get_all("x-span").collect();
+        assert_eq!(span, vec!["a", "b"]);
+        assert!(resp.headers().get(INTERNAL_ADD_HEADER_HEADER).is_none());
+
+        for invalid in ["no-colon", ": value", "bad name: value"] {
+            let req = actix_web::test::TestRequest::get()
+                .uri("/trace")
+                .insert_header((INTERNAL_ADD_HEADER_HEADER, invalid))
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST, "{}", invalid);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_set_cookie() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/login")
+            .append_header((INTERNAL_SET_COOKIE_HEADER, "session=abc; Path=/; HttpOnly | theme=dark"))
+            .append_header((INTERNAL_SET_COOKIE_HEADER, "consent=yes; Max-Age=3600; Secure; SameSite=Lax"))
+            .insert_header(("cookie", "ignored=1"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let set_cookies: Vec<_> = resp
+            .headers()
+            .get_all("set-cookie")
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(set_cookies.len(), 3);
+        assert!(set_cookies.contains(&"session=abc; HttpOnly; Path=/".to_string()));
+        assert!(set_cookies.contains(&"theme=dark".to_string()));
+        assert!(set_cookies.contains(&"consent=yes; SameSite=Lax; Secure; Max-Age=3600".to_string()));
+        assert!(resp.headers().get("cookie").is_none());
+        assert!(resp.headers().get(INTERNAL_SET_COOKIE_HEADER).is_none());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/login")
+            .insert_header((INTERNAL_SET_COOKIE_HEADER, "no-value"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_validate_retry_after() {
+        assert_eq!(validate_retry_after("120").unwrap(), "120");
+        assert_eq!(validate_retry_after(" 0 ").unwrap(), "0");
+        assert_eq!(
+            validate_retry_after("Wed, 21 Oct 2015 07:28:00 GMT").unwrap(),
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+        assert!(validate_retry_after("-5").is_err());
+        assert!(validate_retry_after("1.5").is_err());
+        assert!(validate_retry_after("soon").is_err());
+        assert!(validate_retry_after("").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_strict_mode() {
+        let lenient = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let strict = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { strict: true, ..Config::default() })),
+        )
+        .await;
+
+        let malformed = || {
+            actix_web::test::TestRequest::get()
+                .uri("/strict?status=abc")
+                .insert_header((INTERNAL_STATUS_CODE_HEADER, "2OO"))
+                .insert_header((INTERNAL_FORMAT_HEADER, "xml"))
+                .insert_header((INTERNAL_CONTENT_TYPE_HEADER, "not a mime"))
+                .to_request()
+        };
+
+        let resp = actix_web::test::call_service(&lenient, malformed()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = actix_web::test::call_service(&strict, malformed()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/problem+json");
+        let problem: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["title"], "Bad Request");
+        let errors = problem["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 4);
+        assert!(errors.iter().any(|e| e.as_str().unwrap().contains(INTERNAL_STATUS_CODE_HEADER)));
+        assert!(errors.iter().any(|e| e.as_str().unwrap().contains(INTERNAL_FORMAT_HEADER)));
+
+        // Out of range values that are rejected anyway get a problem+json body too
+        let req = actix_web::test::TestRequest::get()
+            .uri("/strict")
+            .insert_header((INTERNAL_DELAY_MS_HEADER, (MAX_DELAY_MS + 1).to_string()))
+            .to_request();
+        let resp = actix_web::test::call_service(&strict, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/problem+json");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/strict")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "201"))
+            .set_payload("ok")
+            .to_request();
+        let resp = actix_web::test::call_service(&strict, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    #[actix_web::test]
+    async fn test_retry_after() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/busy")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "503"))
+            .insert_header((INTERNAL_RETRY_AFTER_HEADER, "30"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "30");
+        assert!(resp.headers().get(INTERNAL_RETRY_AFTER_HEADER).is_none());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/busy")
+            .insert_header((INTERNAL_RETRY_AFTER_HEADER, "30"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("retry-after").is_none());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/busy")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "503"))
+            .insert_header((INTERNAL_RETRY_AFTER_HEADER, "later"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_validate_cache_control() {
+        assert_eq!(validate_cache_control("no-store").unwrap(), "no-store");
+        assert_eq!(validate_cache_control(" public, max-age=60 ").unwrap(), "public, max-age=60");
+        assert!(validate_cache_control("").is_err());
+        assert!(validate_cache_control("   ").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_cache_control() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/cached")
+            .insert_header((INTERNAL_CACHE_CONTROL_HEADER, "public, max-age=60"))
+            .insert_header(("cache-control", "no-cache"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get_all("cache-control").count(), 1);
+        assert_eq!(resp.headers().get("cache-control").unwrap(), "public, max-age=60");
+        assert!(resp.headers().get(INTERNAL_CACHE_CONTROL_HEADER).is_none());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/cached")
+            .insert_header((INTERNAL_CACHE_CONTROL_HEADER, ""))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/old")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "302"))
+            .insert_header((INTERNAL_LOCATION_HEADER, "/new"))
+            .set_payload("not echoed")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::FOUND);
+        assert_eq!(resp.headers().get("location").unwrap(), "/new");
+        assert!(actix_web::test::read_body(resp).await.is_empty());
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/old")
+            .insert_header((INTERNAL_LOCATION_HEADER, "/new"))
+            .set_payload("echoed")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert!(resp.headers().get("location").is_none());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/old")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "301"))
+            .insert_header((INTERNAL_LOCATION_HEADER, "not a url"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_latency_reservoir() {
+        let mut reservoir = LatencyReservoir::default();
+        assert!(reservoir.summary().is_none());
+
+        for ms in (1..=100).rev() {
+            reservoir.observe(Duration::from_millis(ms));
+        }
+        let summary = reservoir.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.min, Duration::from_millis(1));
+        assert_eq!(summary.p50, Duration::from_millis(50));
+        assert_eq!(summary.p90, Duration::from_millis(90));
+        assert_eq!(summary.p99, Duration::from_millis(99));
+        assert_eq!(summary.max, Duration::from_millis(100));
+
+        // Memory stays bounded, the extremes stay exact
+        for _ in 0..LATENCY_RESERVOIR_SIZE * 2 {
+            reservoir.observe(Duration::from_millis(10));
+        }
+        reservoir.observe(Duration::from_secs(5));
+        assert_eq!(reservoir.samples.len(), LATENCY_RESERVOIR_SIZE);
+        let summary = reservoir.summary().unwrap();
+        assert_eq!(summary.count, 100 + LATENCY_RESERVOIR_SIZE as u64 * 2 + 1);
+        assert_eq!(summary.p50, Duration::from_millis(10));
+        assert_eq!(summary.max, Duration::from_secs(5));
+        assert_eq!(
+            summary.to_string(),
+            format!("{} request(s): min 1.000ms, p50 10.000ms, p90 10.000ms, p99 10.000ms, max 5000.000ms", summary.count)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        for status in ["200", "200", "503"] {
+            let req = actix_web::test::TestRequest::get()
+                .uri("/measured")
+                .insert_header((INTERNAL_STATUS_CODE_HEADER, status))
+                .to_request();
+            actix_web::test::call_service(&app, req).await;
+        }
+
+        let req = actix_web::test::TestRequest::get().uri(METRICS_PATH).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get("content-type").unwrap().to_str().unwrap().starts_with("text/plain"));
+        let body = actix_web::test::read_body(resp).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("grecho_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(body.contains("grecho_requests_total{method=\"GET\",status=\"503\"} 1\n"));
+        assert!(body.contains("grecho_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(body.contains("grecho_request_duration_seconds_count 3\n"));
+
+        // Scraping the metrics isn't counted as a request
+        let req = actix_web::test::TestRequest::get().uri(METRICS_PATH).to_request();
+        let body = actix_web::test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&body).contains("grecho_request_duration_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn test_jittered_delay_ms() {
+        assert_eq!(jittered_delay_ms(100, 0, MAX_DELAY_MS), 100);
+        for _ in 0..1000 {
+            let delay_ms = jittered_delay_ms(100, 20, MAX_DELAY_MS);
+            assert!((80..=120).contains(&delay_ms));
+            // Clamped to zero and to the cap
+            assert!(jittered_delay_ms(5, 20, MAX_DELAY_MS) <= 25);
+            assert!((75..=100).contains(&jittered_delay_ms(95, 20, 100)));
+        }
+        assert!(validate_delay_jitter_ms(MAX_DELAY_MS, MAX_DELAY_MS).is_ok());
+        assert!(validate_delay_jitter_ms(MAX_DELAY_MS + 1, MAX_DELAY_MS).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_delay_jitter() {
+        let app = actix_web::test::init_service(
+            App::new().configure(test_config).app_data(web::Data::new(Config { max_delay_ms: 40, ..Config::default() })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/jitter")
+            .insert_header((INTERNAL_DELAY_MS_HEADER, "20"))
+            .insert_header((INTERNAL_DELAY_JITTER_MS_HEADER, "10"))
+            .to_request();
+        let started_at = Instant::now();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(started_at.elapsed() >= Duration::from_millis(10));
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/jitter")
+            .insert_header((INTERNAL_DELAY_JITTER_MS_HEADER, "50"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_validate_delay_ms() {
+        assert_eq!(validate_delay_ms(0, MAX_DELAY_MS).unwrap(), 0);
+        assert_eq!(validate_delay_ms(1500, MAX_DELAY_MS).unwrap(), 1500);
+        assert_eq!(validate_delay_ms(MAX_DELAY_MS, MAX_DELAY_MS).unwrap(), MAX_DELAY_MS);
+        assert!(validate_delay_ms(MAX_DELAY_MS + 1, MAX_DELAY_MS).is_err());
+    }
+
+    #[test]
+    fn test_query_headers() {
+        let params = vec![
+            ("h_x-custom".to_string(), "foo".to_string()),
+            ("status".to_string(), "503".to_string()),
+            ("h_".to_string(), "empty name".to_string()),
+            ("h_bad header".to_string(), "value".to_string()),
+        ];
+        let headers = query_headers(&params);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].0, "x-custom");
+        assert_eq!(headers[0].1, "foo");
+    }
+
+    #[actix_web::test]
+    async fn test_query_string_controls() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test?h_x-custom=foo&status=503")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("x-custom").unwrap(), "foo");
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/test?status=503")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "404"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_compression() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(Condition::new(true, Compress::default()))
+                .configure(test_config),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/compressed")
+            .insert_header(("accept-encoding", "gzip"))
+            .set_payload("a body that should be compressed")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/identity")
+            .set_payload("a body that should not be compressed")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get("content-encoding").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_cors_preflight_is_not_echoed() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(CorsSettings::default().build())
+                .configure(test_config),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api")
+            .insert_header(("origin", "http://example.com"))
+            .insert_header(("access-control-request-method", "PUT"))
+            .insert_header(("x-custom", "value"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), "http://example.com");
+        assert!(resp.headers().get("x-custom").is_none());
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/api")
+            .insert_header(("origin", "http://example.com"))
+            .insert_header(("x-custom", "value"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), "http://example.com");
+        assert_eq!(resp.headers().get("x-custom").unwrap(), "value");
+    }
+
+    #[actix_web::test]
+    async fn test_cors_section_without_flag() {
+        let section = CorsSettings {
+            allowed_origins: Some(vec!["http://allowed.example".to_string()]),
+            ..CorsSettings::default()
+        };
+        assert!(CorsSettings::resolve(false, Some(&section)).is_none());
+        assert_eq!(
+            CorsSettings::resolve(true, Some(&section)).unwrap().allowed_origins,
+            Some(vec!["http://allowed.example".to_string()])
+        );
+        assert!(CorsSettings::resolve(true, None).unwrap().allowed_origins.is_none());
+
+        // Without the CORS layer a preflight request is echoed like any other
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+        let req = actix_web::test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api")
+            .insert_header(("origin", "http://allowed.example"))
+            .insert_header(("access-control-request-method", "PUT"))
+            .insert_header(("x-custom", "value"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+        assert_eq!(resp.headers().get("x-custom").unwrap(), "value");
+    }
+
+    #[actix_web::test]
+    async fn test_cors_rejects_unlisted_origin() {
+        let cors_settings = CorsSettings {
+            allowed_origins: Some(vec!["http://allowed.example".to_string()]),
+            ..CorsSettings::default()
+        };
+        let app = actix_web::test::init_service(
+            App::new().wrap(cors_settings.build()).configure(test_config),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/api")
+            .insert_header(("origin", "http://other.example"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_handshake() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(WEBSOCKET_PATH)
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            resp.headers().get("sec-websocket-accept").unwrap(),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_echo() {
+        use awc::ws::{CloseCode, CloseReason, Frame, Message};
+        use futures_util::{SinkExt, StreamExt};
+
+        let (addr, handle) = start_test_server();
+        let (_, mut socket) = awc::Client::new()
+            .ws(format!("ws://{}{}", addr, WEBSOCKET_PATH))
+            .connect()
+            .await
+            .unwrap();
+
+        socket.send(Message::Text("hello".into())).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Frame::Text(web::Bytes::from_static(b"hello")));
+
+        socket.send(Message::Binary(web::Bytes::from_static(&[0, 159, 255]))).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Frame::Binary(web::Bytes::from_static(&[0, 159, 255])));
+
+        socket.send(Message::Ping(web::Bytes::from_static(b"beat"))).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Frame::Pong(web::Bytes::from_static(b"beat")));
+
+        // The close frame is answered with the same reason, then the connection ends
+        let reason = CloseReason { code: CloseCode::Normal, description: Some("done".to_string()) };
+        socket.send(Message::Close(Some(reason.clone()))).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Frame::Close(Some(reason)));
+        assert!(socket.next().await.is_none());
+
+        handle.stop(false).await;
+    }
+
+    // Starts a real server on a random port, for behaviors that the test service can't observe
+    fn start_test_server() -> (SocketAddr, actix_web::dev::ServerHandle) {
+        start_test_server_with(Config::default())
+    }
+
+    fn start_test_server_with(config: Config) -> (SocketAddr, actix_web::dev::ServerHandle) {
+        let config = web::Data::new(config);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = HttpServer::new(move || App::new().configure(test_config).app_data(config.clone()))
+            .listen(listener)
+            .unwrap()
+            .workers(1)
+            .disable_signals()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        (addr, handle)
+    }
+
+    #[actix_web::test]
+    async fn test_self_check() {
+        let config = ServerConfig {
+            tls_cert: Some("tests/fixtures/cert.pem".to_string()),
+            tls_key: Some("tests/fixtures/key.pem".to_string()),
+            auth: Some("user:pass".to_string()),
+            ..ServerConfig::default()
+        };
+        let server = start_server(config.clone()).await.unwrap();
+        let addr = server.addrs()[0];
+        let check_config = config.clone();
+        let status = actix_web::rt::task::spawn_blocking(move || self_check(&check_config, Some(addr), true)).await.unwrap();
+        assert_eq!(status.unwrap(), "200 OK");
+
+        // The self-check isn't counted
+        let stats = actix_web::rt::task::spawn_blocking(move || {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let connection = self_check_tls_connection("tests/fixtures/cert.pem").unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", STATS_PATH);
+            exchange(rustls::StreamOwned::new(connection, stream), &request).unwrap()
+        })
+        .await
+        .unwrap();
+        assert!(String::from_utf8_lossy(&stats).contains("\"total\":0,"));
+        server.stop(true).await;
+        server.wait().await.unwrap();
+
+        // Nothing listens anymore
+        let result = actix_web::rt::task::spawn_blocking(move || self_check(&config, Some(addr), true)).await.unwrap();
+        assert!(result.unwrap_err().starts_with("Could not connect"));
+
+        assert_eq!(self_check_address("0.0.0.0:80".parse().unwrap()), "127.0.0.1:80".parse().unwrap());
+        assert_eq!(self_check_address("[::]:80".parse().unwrap()), "[::1]:80".parse().unwrap());
+    }
+
+    #[test]
+    fn test_validate_drip_ms() {
+        assert_eq!(drip_chunk_size(0), 1);
+        assert_eq!(drip_chunk_size(MAX_DRIP_CHUNKS), 1);
+        assert_eq!(drip_chunk_size(MAX_DRIP_CHUNKS + 1), 2);
+        assert_eq!(validate_drip_ms(100, 11, 1000), Ok(100));
+        assert!(validate_drip_ms(100, 12, 1000).is_err());
+        // Large bodies are sent in at most MAX_DRIP_CHUNKS chunks
+        assert!(validate_drip_ms(1, 10 * 1024 * 1024, MAX_DELAY_MS).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_drip() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/")
+            .insert_header((INTERNAL_DRIP_MS_HEADER, "20"))
+            .set_payload("abcd")
+            .to_request();
+        let started_at = Instant::now();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(actix_web::http::header::CONTENT_ENCODING).unwrap(), "identity");
+        assert_eq!(resp.response().body().size(), BodySize::Sized(4));
+        assert_eq!(actix_web::test::read_body(resp).await, "abcd");
+        assert!(started_at.elapsed() >= Duration::from_millis(60));
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/")
+            .insert_header((INTERNAL_DRIP_MS_HEADER, MAX_DELAY_MS.to_string()))
+            .set_payload("ab")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/")
+            .insert_header((INTERNAL_DRIP_MS_HEADER, MAX_DELAY_MS.to_string()))
+            .set_payload("abc")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_expect_continue() {
+        let (addr, handle) = start_test_server();
+        let (interim, response) = actix_web::rt::task::spawn_blocking(move || {
+            use std::io::{Read, Write};
+
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+            // The body is only sent once the server asked for it
+            let mut interim = [0u8; 25];
+            stream.read_exact(&mut interim).unwrap();
+            stream.write_all(b"hello").unwrap();
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response);
+            (interim, String::from_utf8(response).unwrap())
+        })
+        .await
+        .unwrap();
+        handle.stop(false).await;
+
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        assert!(response.contains("expect: 100-continue\r\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_preserve_header_case() {
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nX-Custom-Header: value\r\nConnection: close\r\n\r\n";
+
+        let (addr, handle) = start_test_server();
+        let response = String::from_utf8(raw_request(addr, request).await).unwrap();
+        assert!(response.contains("\r\nx-custom-header: value\r\n"));
+        handle.stop(false).await;
+
+        let (addr, handle) = start_test_server_with(Config { preserve_header_case: true, ..Config::default() });
+        let response = String::from_utf8(raw_request(addr, request).await).unwrap();
+        assert!(response.contains("\r\nX-Custom-Header: value\r\n"));
+        assert!(response.contains("\r\nContent-Length: "));
+        handle.stop(false).await;
+    }
+
+    // Sends a raw HTTP request and reads until the server closes the connection
+    async fn raw_request(addr: SocketAddr, request: &'static str) -> Vec<u8> {
+        actix_web::rt::task::spawn_blocking(move || {
+            use std::io::{Read, Write};
+
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response);
+            response
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_content_length() {
+        assert_eq!(validate_content_length("0").unwrap(), 0);
+        assert_eq!(validate_content_length(" 1024 ").unwrap(), 1024);
+        assert!(validate_content_length("-1").is_err());
+        assert!(validate_content_length("ten").is_err());
+        assert!(validate_content_length("").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_content_length_mismatch() {
+        let (addr, handle) = start_test_server();
+
+        // Shorter than the body: the whole body is still sent
+        let response = raw_request(
+            addr,
+            "POST /short HTTP/1.1\r\nHost: localhost\r\ninternal.content-length: 3\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("content-length: 3\r\n"));
+        assert!(!head.contains("transfer-encoding"));
+        assert!(!head.contains(INTERNAL_CONTENT_LENGTH_HEADER));
+        assert_eq!(body, "hello");
+
+        // Longer than the body: the connection is closed after the body, without keep-alive
+        let response = raw_request(
+            addr,
+            "POST /long HTTP/1.1\r\nHost: localhost\r\ninternal.content-length: 100\r\nContent-Length: 2\r\n\r\nhi",
+        )
+        .await;
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("content-length: 100\r\n"));
+        assert_eq!(body, "hi");
+
+        // HEAD gets the forced Content-Length without a body
+        let response = raw_request(
+            addr,
+            "HEAD /head HTTP/1.1\r\nHost: localhost\r\ninternal.content-length: 7\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("content-length: 7\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn test_parse_trailers() {
+        let trailers = parse_trailers(["X-Checksum: abc", "x-done:yes"].into_iter()).unwrap();
+        assert_eq!(trailers.len(), 2);
+        assert_eq!(trailers[0].0, "x-checksum");
+        assert_eq!(trailers[1].1, "yes");
+        assert!(parse_trailers(["no-colon"].into_iter()).is_err());
+        assert!(parse_trailers(["Content-Length: 5"].into_iter()).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_trailers() {
+        let (addr, handle) = start_test_server();
+
+        let response = raw_request(
+            addr,
+            "POST /trailers HTTP/1.1\r\nHost: localhost\r\ninternal.trailer: X-Checksum: abc\r\n\
+             internal.trailer: X-Done: yes\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        )
+        .await;
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("transfer-encoding: chunked\r\n"));
+        assert!(head.contains("trailer: x-checksum, x-done\r\n"));
+        assert!(!head.contains("content-length"));
+        assert_eq!(body, "5\r\nhello\r\n0\r\nx-checksum: abc\r\nx-done: yes\r\n\r\n");
+
+        // HTTP/1.0 has no chunked encoding, the header is ignored
+        let response = raw_request(
+            addr,
+            "POST /trailers HTTP/1.0\r\nHost: localhost\r\ninternal.trailer: X-Done: yes\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(!response.contains("x-done"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_stream_body() {
+        let (addr, handle) = start_test_server();
+
+        let response = raw_request(
+            addr,
+            "POST /stream HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\ninternal.stream: true\r\nx-custom: value\r\nContent-Length: 11\r\n\r\nhello world",
+        )
+        .await;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let head = head.to_lowercase();
+        assert!(head.starts_with("http/1.1 200 ok"));
+        assert!(head.contains("transfer-encoding: chunked"));
+        assert!(head.contains("x-custom: value"));
+        assert!(!head.contains("content-length"));
+        assert!(!head.contains(INTERNAL_STREAM_HEADER));
+
+        // Decode the chunked body
+        let mut decoded = String::new();
+        let mut rest = body;
+        loop {
+            let (size, after) = rest.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            decoded.push_str(&after[..size]);
+            rest = &after[size + 2..];
+        }
+        assert_eq!(decoded, "hello world");
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_connection_close_and_abort() {
+        let (addr, handle) = start_test_server();
+
+        let response = raw_request(
+            addr,
+            "GET /close HTTP/1.1\r\nHost: localhost\r\ninternal.connection: close\r\n\r\n",
+        )
+        .await;
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("connection: close"));
+
+        let response = raw_request(
+            addr,
+            "GET /abort HTTP/1.1\r\nHost: localhost\r\ninternal.abort: true\r\n\r\n",
+        )
+        .await;
+        assert!(response.is_empty());
+
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn test_parse_header_list() {
+        let list = parse_header_list("X-One, x-two,,  ");
+        assert_eq!(list.len(), 2);
+        assert!(list.contains("x-one"));
+        assert!(list.contains("x-two"));
+    }
+
+    #[actix_web::test]
+    async fn test_echo_headers_allowlist() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri("/subset")
+            .insert_header((INTERNAL_ECHO_HEADERS_HEADER, "X-Keep, host"))
+            .insert_header(("x-keep", "1"))
+            .insert_header(("x-drop", "2"))
+            .insert_header(("host", "example.com"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-keep").unwrap(), "1");
+        assert!(resp.headers().get("x-drop").is_none());
+        assert!(resp.headers().get("host").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_dual_stack() {
+        let listener = dual_stack_listener("[::]:0".parse().unwrap(), DEFAULT_LISTEN_BACKLOG).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer::new(|| App::new().configure(test_config))
+            .listen(listener)
+            .unwrap()
+            .workers(1)
+            .disable_signals()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        for addr in [format!("127.0.0.1:{}", port), format!("[::1]:{}", port)] {
+            let response = raw_request(
+                addr.parse().unwrap(),
+                "GET /dual HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200 OK"), "{}", addr);
+        }
+
+        handle.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn test_h2c_round_trip() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = HttpServer::new(|| App::new().configure(test_config))
+            .listen_auto_h2c(listener)
+            .unwrap()
+            .workers(1)
+            .disable_signals()
+            .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let tcp = actix_web::rt::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(tcp).await.unwrap();
+        actix_web::rt::spawn(connection);
+
+        let request = http::Request::builder()
+            .method("POST")
+            .uri(format!("http://{}/h2", addr))
+            .header("x-custom", "value")
+            .body(())
+            .unwrap();
+        let mut client = client.ready().await.unwrap();
+        let (response, mut send_stream) = client.send_request(request, false).unwrap();
+        send_stream.send_data(web::Bytes::from("hello over h2"), true).unwrap();
+
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        assert_eq!(response.headers().get("x-custom").unwrap(), "value");
+
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            let _ = body.flow_control().release_capacity(chunk.len());
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, b"hello over h2");
+
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn test_parse_content_type() {
+        assert_eq!(parse_content_type("application/json").unwrap(), actix_web::mime::APPLICATION_JSON);
+        assert!(parse_content_type("text/plain; charset=utf-8").is_some());
+        assert!(parse_content_type("not a mime type").is_none());
+        assert!(parse_content_type("").is_none());
+    }
+
+    #[test]
+    fn test_settings_load() {
+        let settings = Settings::load(DEFAULT_SETTINGS_PATH).unwrap();
+        assert_eq!(settings.host, "127.0.0.1");
+        assert_eq!(settings.port, 8001);
+        assert!(Settings::load("tests/fixtures/missing.toml").is_err());
+        assert!(Settings::load("tests/fixtures/cert.pem").unwrap_err().to_string().starts_with("Invalid TOML"));
+    }
+
+    #[test]
+    fn test_settings_load_formats() {
+        for path in ["tests/fixtures/settings.json", "tests/fixtures/settings.yaml"] {
+            let settings = Settings::load(path).unwrap();
+            assert_eq!(settings.host, "0.0.0.0", "{}", path);
+            assert_eq!(settings.port, 9000, "{}", path);
+            assert_eq!(settings.default_status, Some(202), "{}", path);
+            assert_eq!(settings.cors.unwrap().allowed_origins, Some(vec!["http://localhost:3000".to_string()]), "{}", path);
+            assert_eq!(settings.routes.len(), 1, "{}", path);
+            assert_eq!(settings.routes[0].body.as_deref(), Some("{\"id\": 1}"), "{}", path);
+        }
+
+        // Errors name the format the file was parsed as
+        assert!(Settings::load("tests/fixtures/template.json").unwrap_err().to_string().starts_with("Invalid JSON"));
+    }
+
+    #[test]
+    fn test_reserved_headers_from_settings() {
+        let defaults = ReservedHeaders::from_settings(&Settings::default(), None);
+        assert!(defaults.contains("Authorization"));
+        assert!(!defaults.contains("x-debug"));
+
+        let settings = Settings {
+            reserved_headers: Some(vec!["Host".to_string()]),
+            extra_reserved_headers: Some(vec!["X-Debug".to_string()]),
+            ..Settings::default()
+        };
+        let replaced = ReservedHeaders::from_settings(&settings, None);
+        assert!(replaced.contains("host"));
+        assert!(replaced.contains("x-debug"));
+        assert!(!replaced.contains("authorization"));
+
+        // The file takes precedence over reserved_headers, the extra headers are still added
+        let from_file = ReservedHeaders::from_settings(&settings, Some(&["X-Token".to_string()]));
+        assert!(from_file.contains("x-token"));
+        assert!(from_file.contains("x-debug"));
+        assert!(!from_file.contains("host"));
+    }
+
+    #[test]
+    fn test_load_reserved_headers_file() {
+        let path = std::env::temp_dir().join(format!("grecho-reserved-{}.txt", std::process::id()));
+        let write = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            load_reserved_headers_file(path.to_str().unwrap())
+        };
+        assert_eq!(write("# shared list\nHost\n\n  x-token  \n").unwrap(), ["Host", "x-token"]);
+        assert!(write("host\nnot a header\n").unwrap_err().contains("line 2"));
+        assert!(write("# nothing\n\n").unwrap_err().contains("does not list any header"));
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_reserved_headers_file(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_validate_repeat_count() {
+        let (max_count, max_size) = (MAX_REPEAT_BODY_COUNT, MAX_REPEATED_BODY_SIZE);
+        assert_eq!(validate_repeat_count(0, 10, max_count, max_size).unwrap(), 0);
+        assert_eq!(validate_repeat_count(3, 10, max_count, max_size).unwrap(), 3);
+        assert!(validate_repeat_count(max_count + 1, 1, max_count, max_size).is_err());
+        assert!(validate_repeat_count(max_count, max_size, max_count, max_size).is_err());
+        assert!(validate_repeat_count(3, 10, 2, max_size).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_repeat_body() {
+        let app = actix_web::test::init_service(App::new().configure(test_config)).await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/repeat")
+            .insert_header((INTERNAL_REPEAT_BODY_HEADER, "3"))
+            .set_payload("ab")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.headers().get(INTERNAL_REPEAT_BODY_HEADER).is_none());
+        assert_eq!(actix_web::test::read_body(resp).await, "ababab");
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/repeat")
+            .insert_header((INTERNAL_REPEAT_BODY_HEADER, (MAX_REPEAT_BODY_COUNT + 1).to_string()))
+            .set_payload("ab")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_template_parse() {
+        assert_eq!(
+            Template::parse("a {method} {unknown} {{x}} {header.x-id}").0,
+            [
+                TemplatePart::Text("a ".to_string()),
+                TemplatePart::Method,
+                TemplatePart::Text(" {unknown} {x} ".to_string()),
+                TemplatePart::Header("x-id".to_string()),
+            ]
+        );
+        assert_eq!(Template::parse("").0, []);
+    }
+
+    #[actix_web::test]
+    async fn test_template_file() {
+        assert!(Template::load("tests/fixtures/missing.txt").is_err());
+
+        for strict in [false, true] {
+            let app = actix_web::test::init_service(
+                App::new()
+                    .configure(test_config)
+                    .app_data(web::Data::new(Config {
+                        template_file: Some(Template::load("tests/fixtures/template.json").unwrap()),
+                        strict,
+                        ..Config::default()
+                    })),
+            )
+            .await;
+
+            let req = actix_web::test::TestRequest::post()
+                .uri("/orders?page=2")
+                .insert_header(("x-tenant", "acme"))
+                .set_payload("ignored")
+                .to_request();
+            let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, serde_json::json!({"method": "POST", "path": "/orders", "tenant": "acme", "page": "2"}));
+
+            // Missing values are left empty, or rejected in strict mode
+            let req = actix_web::test::TestRequest::get().uri("/orders").to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            if strict {
+                assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+                let problem: serde_json::Value = actix_web::test::read_body_json(resp).await;
+                assert_eq!(problem["errors"].as_array().unwrap().len(), 2);
+            } else {
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+                assert_eq!(body["tenant"], "");
+            }
+
+            let req = actix_web::test::TestRequest::get()
+                .uri("/orders")
+                .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "override"))
+                .to_request();
+            assert_eq!(actix_web::test::call_and_read_body(&app, req).await, "override");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_response_file() {
+        assert!(ResponseFile::load("tests/fixtures/missing.txt").is_err());
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(test_config)
+                .app_data(web::Data::new(Config {
+                    response_file: ResponseFile::load("tests/fixtures/cert.pem").unwrap(),
+                    ..Config::default()
+                })),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/file")
+            .insert_header((INTERNAL_STATUS_CODE_HEADER, "201"))
+            .set_payload("ignored")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CREATED);
+        let body = actix_web::test::read_body(resp).await;
+        assert_eq!(body, std::fs::read("tests/fixtures/cert.pem").unwrap());
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/file")
+            .insert_header((INTERNAL_RESPONSE_BODY_HEADER, "override"))
+            .to_request();
+        assert_eq!(actix_web::test::call_and_read_body(&app, req).await, "override");
+    }
+
+    #[test]
+    fn test_is_echoed_header() {
+        let reserved_headers = ReservedHeaders::from_settings(&Settings::default(), None);
+        assert!(is_echoed_header("x-custom", &reserved_headers));
+        assert!(!is_echoed_header("Host", &reserved_headers));
+        assert!(!is_echoed_header(INTERNAL_DELAY_MS_HEADER, &reserved_headers));
+        assert!(!is_echoed_header("Internal.Status-Code", &reserved_headers));
+    }
+}
@@ -0,0 +1,404 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use grecho::{check_config, start_server, ServerConfig};
+
+// Sends a raw HTTP request and reads until the server closes the connection
+fn raw_request(addr: std::net::SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_start_and_stop_server() {
+    let server = start_server(ServerConfig::default()).await.unwrap();
+    let addr = server.addrs()[0];
+    assert_ne!(addr.port(), 0);
+    assert_eq!(server.url().unwrap(), format!("http://{}", addr));
+
+    let response = tokio::task::spawn_blocking(move || {
+        raw_request(
+            addr,
+            "POST /hello HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nX-Test: 1\r\nConnection: close\r\n\r\nhello",
+        )
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.to_lowercase().contains("x-test: 1"));
+    assert!(response.ends_with("hello"));
+    assert_eq!(server.requests_total(), 1);
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[tokio::test]
+async fn test_invalid_config() {
+    let config = ServerConfig {
+        default_status: 42,
+        ..ServerConfig::default()
+    };
+    let error = start_server(config).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_check_config() {
+    check_config(&ServerConfig::default()).unwrap();
+
+    let config = ServerConfig {
+        tls_cert: Some("tests/fixtures/cert.pem".to_string()),
+        tls_key: Some("tests/fixtures/missing.pem".to_string()),
+        ..ServerConfig::default()
+    };
+    assert_eq!(check_config(&config).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+    // The record file isn't created by a check
+    let record = std::env::temp_dir().join(format!("grecho-check-config-{}.jsonl", std::process::id()));
+    let config = ServerConfig {
+        record: Some(record.to_string_lossy().into_owned()),
+        ..ServerConfig::default()
+    };
+    check_config(&config).unwrap();
+    assert!(!record.exists());
+}
+
+#[tokio::test]
+async fn test_base_path() {
+    let config = ServerConfig {
+        base_path: "/echo/".to_string(),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+    assert_eq!(server.url().unwrap(), format!("http://{}/echo", addr));
+
+    let get = |path: &'static str| {
+        tokio::task::spawn_blocking(move || {
+            raw_request(addr, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\nX-Test: 1\r\nConnection: close\r\n\r\n", path))
+        })
+    };
+    for path in ["/echo", "/echo/", "/echo/users/1?a=b"] {
+        let response = get(path).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", path);
+        assert!(response.to_lowercase().contains("x-test: 1"), "{}", path);
+    }
+    let response = get("/echo/health").await.unwrap();
+    assert!(response.ends_with(r#"{"status":"ok"}"#));
+    let response = get("/echo/stats").await.unwrap();
+    assert!(response.contains(r#""paths":{"/echo":1,"/echo/":1,"/echo/users/1":1}"#));
+
+    for path in ["/", "/health", "/echoes", "/other/echo"] {
+        let response = get(path).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", path);
+    }
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_base_path_auth() {
+    let config = ServerConfig {
+        base_path: "/echo".to_string(),
+        auth: Some("user:pass".to_string()),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+
+    let post = |path: &'static str, credentials: &'static str| {
+        tokio::task::spawn_blocking(move || {
+            raw_request(
+                addr,
+                &format!(
+                    "POST {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: 5\r\nConnection: close\r\n\r\nhello",
+                    path, credentials
+                ),
+            )
+        })
+    };
+    // The bare base path is guarded like every path below it
+    for path in ["/echo", "/echo/", "/echo/x"] {
+        let response = post(path, "").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized"), "{}", path);
+        // "user:pass"
+        let response = post(path, "Authorization: Basic dXNlcjpwYXNz\r\n").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", path);
+        assert!(response.ends_with("hello"), "{}", path);
+    }
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_no_continue() {
+    let config = ServerConfig {
+        no_continue: true,
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+
+    // The body is never sent, the final response comes without an interim one
+    let response = tokio::task::spawn_blocking(move || {
+        raw_request(addr, "POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n")
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "{}", response);
+    assert!(!response.contains("100 Continue"));
+
+    // The client's retry without the expectation is echoed
+    let response = tokio::task::spawn_blocking(move || {
+        raw_request(addr, "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello")
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nhello"));
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+
+    let config = ServerConfig {
+        no_continue: true,
+        uds: Some("/tmp/grecho-no-continue.sock".to_string()),
+        ..ServerConfig::default()
+    };
+    assert!(check_config(&config).is_err());
+}
+
+#[tokio::test]
+async fn test_keep_alive_disabled() {
+    let config = ServerConfig {
+        keep_alive_secs: 0,
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+
+    // The server hangs up after the response, although the client didn't ask it to
+    let response = tokio::task::spawn_blocking(move || raw_request(addr, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"))
+        .await
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("connection: close"));
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+}
+
+// Sends a raw HTTP request over TLS, presenting the test client certificate when `with_client_cert`
+fn raw_tls_request(addr: std::net::SocketAddr, request: &str, with_client_cert: bool) -> std::io::Result<String> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file("tests/fixtures/client-ca.pem").unwrap()).unwrap();
+    let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+    let config = if with_client_cert {
+        let cert = CertificateDer::from_pem_file("tests/fixtures/client-cert.pem").unwrap();
+        let key = PrivateKeyDer::from_pem_file("tests/fixtures/client-key.pem").unwrap();
+        builder.with_client_auth_cert(vec![cert], key).unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let connection = rustls::ClientConnection::new(config.into(), ServerName::try_from("localhost").unwrap()).unwrap();
+    let mut stream = rustls::StreamOwned::new(connection, TcpStream::connect(addr)?);
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    let config = ServerConfig {
+        tls_cert: Some("tests/fixtures/server-cert.pem".to_string()),
+        tls_key: Some("tests/fixtures/server-key.pem".to_string()),
+        tls_client_ca: Some("tests/fixtures/client-ca.pem".to_string()),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+    assert!(server.url().unwrap().starts_with("https://"));
+
+    // A client can't set the subject itself, the header is replaced
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\nX-Client-Cert-Subject: admin\r\nConnection: close\r\n\r\n";
+    let response = tokio::task::spawn_blocking(move || raw_tls_request(addr, request, true)).await.unwrap().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("x-client-cert-subject: grecho-test-client\r\n"));
+    assert!(!response.contains("admin"));
+
+    let response = tokio::task::spawn_blocking(move || raw_tls_request(addr, request, false)).await.unwrap();
+    assert!(response.is_err());
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_http_and_https() {
+    let config = ServerConfig {
+        https_bind_addresses: vec![std::net::SocketAddr::from(([127, 0, 0, 1], 0))],
+        tls_cert: Some("tests/fixtures/server-cert.pem".to_string()),
+        tls_key: Some("tests/fixtures/server-key.pem".to_string()),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let (http_addr, https_addr) = (server.addrs()[0], server.addrs()[1]);
+    assert_eq!(server.urls(), [format!("http://{}", http_addr), format!("https://{}", https_addr)]);
+    assert_eq!(server.url().unwrap(), format!("http://{}", http_addr));
+
+    let request = "GET / HTTP/1.1\r\nHost: localhost\r\nX-Test: 1\r\nConnection: close\r\n\r\n";
+    let response = tokio::task::spawn_blocking(move || raw_request(http_addr, request)).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let response = tokio::task::spawn_blocking(move || raw_tls_request(https_addr, request, false)).await.unwrap().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("x-test: 1\r\n"));
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+
+    // HTTPS addresses need a certificate
+    let config = ServerConfig {
+        https_bind_addresses: vec![std::net::SocketAddr::from(([127, 0, 0, 1], 0))],
+        ..ServerConfig::default()
+    };
+    assert_eq!(check_config(&config).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[tokio::test]
+async fn test_shutdown_endpoint() {
+    let post = |addr, token: &'static str| {
+        tokio::task::spawn_blocking(move || {
+            raw_request(
+                addr,
+                &format!("POST /__shutdown HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", token),
+            )
+        })
+    };
+
+    let server = start_server(ServerConfig::default()).await.unwrap();
+    let response = post(server.addrs()[0], "secret").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+    server.stop(true).await;
+    server.wait().await.unwrap();
+
+    let config = ServerConfig {
+        shutdown_token: Some("secret".to_string()),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+    let response = post(addr, "guess").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+    let response = post(addr, "secret-and-more").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+    // Other methods are echoed
+    let response = tokio::task::spawn_blocking(move || {
+        raw_request(addr, "GET /__shutdown HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n")
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let response = post(addr, "secret").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 202 Accepted"), "{}", response);
+
+    server.shutdown_requested().await;
+    server.stop(true).await;
+    server.wait().await.unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[tokio::test]
+async fn test_max_connections() {
+    let config = ServerConfig {
+        max_connections: Some(1),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+
+    tokio::task::spawn_blocking(move || {
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n";
+        let read_status = |stream: &mut TcpStream| {
+            let mut status = [0; 15];
+            stream.read_exact(&mut status).map(|_| status)
+        };
+
+        // The first connection is kept alive, so it holds the only slot
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.write_all(request).unwrap();
+        assert_eq!(&read_status(&mut first).unwrap(), b"HTTP/1.1 200 OK");
+
+        // The second one is queued in the backlog without being served
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.set_read_timeout(Some(std::time::Duration::from_millis(300))).unwrap();
+        second.write_all(request).unwrap();
+        assert!(read_status(&mut second).is_err());
+
+        drop(first);
+        second.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        assert_eq!(&read_status(&mut second).unwrap(), b"HTTP/1.1 200 OK");
+    })
+    .await
+    .unwrap();
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_backlog() {
+    let config = ServerConfig {
+        backlog: Some(0),
+        ..ServerConfig::default()
+    };
+    assert_eq!(check_config(&config).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+    let config = ServerConfig {
+        backlog: Some(16),
+        ..ServerConfig::default()
+    };
+    let server = start_server(config).await.unwrap();
+    let addr = server.addrs()[0];
+    let response = tokio::task::spawn_blocking(move || raw_request(addr, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"))
+        .await
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    server.stop(true).await;
+    server.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_favicon() {
+    for (echo_favicon, status) in [(false, "HTTP/1.1 204 No Content"), (true, "HTTP/1.1 200 OK")] {
+        let config = ServerConfig {
+            echo_favicon,
+            ..ServerConfig::default()
+        };
+        let server = start_server(config).await.unwrap();
+        let addr = server.addrs()[0];
+
+        let response = tokio::task::spawn_blocking(move || {
+            raw_request(addr, "GET /favicon.ico HTTP/1.1\r\nHost: localhost\r\nX-Test: 1\r\nConnection: close\r\n\r\n")
+        })
+        .await
+        .unwrap();
+        assert!(response.starts_with(status), "{}", response);
+        assert_eq!(response.to_lowercase().contains("x-test: 1"), echo_favicon);
+
+        server.stop(true).await;
+        server.wait().await.unwrap();
+    }
+}